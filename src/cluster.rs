@@ -1,43 +1,345 @@
 use crate::config::Config;
-use crate::fatal;
-use crate::utils::avro_data_to_file_list;
+use crate::inflight::InFlight;
+use crate::memcache::MemoryCache;
+use crate::state::{ClusterState, InvalidTransition, StateHandle};
+use crate::stats::Counters;
+use crate::storage::{self, Storage};
+use crate::tls::{CertError, CertPair};
+use crate::utils::{avro_data_to_file_list, safe_write_file, FileHash};
 use crate::PROTOCOL_VERSION;
 
 use futures_util::FutureExt;
-use reqwest::{Client as reqClient, StatusCode};
+use rand::Rng;
+use reqwest::{header, Client as reqClient, StatusCode};
 use rust_socketio::{
     asynchronous::{Client, ClientBuilder},
     Payload, TransportType,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot, RwLock};
 use tracing::{debug, info, warn};
 use zstd::stream::decode_all;
 
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-#[derive(Deserialize, Debug, Clone)]
+/// keep-alive 间隔
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+/// keep-alive 连续失败多少次后重新 enable
+const KEEP_ALIVE_MAX_FAILURES: u32 = 3;
+/// 重连退避的初始间隔
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+/// 重连退避的最大间隔
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// 文件列表快照的文件名
+pub const FILE_LIST_SNAPSHOT: &str = "filelist.json";
+/// 多久完整获取一次文件列表
+const FILE_LIST_FULL_FETCH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct SyncFile {
     pub path: String,
-    pub hash: String,
+    pub hash: FileHash,
     pub size: i64,
+    /// 毫秒时间戳, 旧的快照中没有这个字段
+    #[serde(default)]
+    pub mtime: i64,
+}
+
+/// hash -> path, 用于猜测下载的 Content-Type
+#[derive(Clone, Default)]
+pub struct FileIndex {
+    inner: Arc<std::sync::RwLock<HashMap<String, String>>>,
+}
+
+impl FileIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 用新的文件列表替换
+    pub fn update(&self, files: &[SyncFile]) {
+        let index = files
+            .iter()
+            .map(|file| (file.hash.to_string(), file.path.clone()))
+            .collect();
+        *self.inner.write().unwrap() = index;
+    }
+
+    pub fn path_of(&self, hash: &str) -> Option<String> {
+        self.inner.read().unwrap().get(hash).cloned()
+    }
+}
+
+/// 上一次获取到的文件列表, 保存在 cache_dir 下
+/// 下一次获取时只请求变化的部分
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileListSnapshot {
+    /// 文件列表中最大的 mtime, 作为 lastModified 参数发给 center
+    /// 取自文件列表本身而不是本地时钟, 本地时钟比 center 快时也不会漏掉变化
+    pub last_modified: i64,
+    /// center 返回的 Last-Modified 头, 原样作为 If-Modified-Since
+    pub last_modified_header: Option<String>,
+    /// 上一次完整获取文件列表的毫秒时间戳
+    pub full_fetched_at: i64,
+    pub files: Vec<SyncFile>,
+}
+
+impl FileListSnapshot {
+    pub fn path(cache_dir: &Path) -> PathBuf {
+        cache_dir.join(FILE_LIST_SNAPSHOT)
+    }
+
+    pub async fn load(cache_dir: &Path) -> Option<Self> {
+        let raw = tokio::fs::read(Self::path(cache_dir)).await.ok()?;
+        match serde_json::from_slice(&raw) {
+            Ok(snapshot) => Some(snapshot),
+            Err(err) => {
+                warn!("parse file list snapshot error: {:?}", err);
+                None
+            }
+        }
+    }
+
+    pub async fn save(&self, cache_dir: &Path) -> Result<(), std::io::Error> {
+        let raw = serde_json::to_vec(self).map_err(std::io::Error::from)?;
+        safe_write_file(&Self::path(cache_dir), &raw).await
+    }
+
+    /// 是否太久没有完整获取过了
+    /// 增量获取不会告诉我们哪些文件被删除了, 所以需要定期完整获取一次
+    pub fn need_full_fetch(&self, now: i64) -> bool {
+        now - self.full_fetched_at > FILE_LIST_FULL_FETCH_INTERVAL.as_millis() as i64
+    }
+
+    /// 文件列表中最大的 mtime, 列表里没有 mtime 时为 None
+    pub fn max_mtime(&self) -> Option<i64> {
+        self.files
+            .iter()
+            .map(|file| file.mtime)
+            .max()
+            .filter(|&mtime| mtime > 0)
+    }
+
+    /// 把变化的文件合并进来, 同一个 path 以新的为准
+    pub fn merge(&mut self, changed: Vec<SyncFile>) {
+        let mut index: HashMap<String, usize> = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, file)| (file.path.clone(), i))
+            .collect();
+        for file in changed {
+            match index.get(&file.path) {
+                Some(&i) => self.files[i] = file,
+                None => {
+                    index.insert(file.path.clone(), self.files.len());
+                    self.files.push(file);
+                }
+            }
+        }
+    }
+}
+
+/// socket.io ack 的错误
+#[derive(Debug, Clone)]
+pub enum AckError {
+    /// emit 本身失败
+    Emit(String),
+    /// 等待 ack 超时
+    Timeout,
+    /// center 返回了 err
+    Center(Value),
+    /// ack 的格式不对
+    BadPayload(String),
+    /// 当前状态不允许这个操作
+    InvalidState(InvalidTransition),
+}
+
+impl std::fmt::Display for AckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Emit(err) => write!(f, "emit failed: {}", err),
+            Self::Timeout => f.write_str("ack timed out"),
+            Self::Center(err) => write!(f, "center returned error: {}", err),
+            Self::BadPayload(payload) => write!(f, "bad ack payload: {}", payload),
+            Self::InvalidState(err) => {
+                write!(
+                    f,
+                    "invalid state transition: {:?} -> {:?}",
+                    err.from, err.to
+                )
+            }
+        }
+    }
+}
+
+/// enable 时发给 center 的 flavor
+#[derive(Serialize, Debug, Clone)]
+pub struct Flavor {
+    pub runtime: String,
+    pub storage: String,
+}
+
+/// enable 时发给 center 的数据
+/// ```typescript
+/// this.socket.emit('enable', {
+///   host: this.host,
+///   port: this.publicPort,
+///   version: config.version,
+///   byoc: this.isByoc,
+///   noFastEnable: process.env.NO_FAST_ENABLE === 'true',
+///   flavor: {
+///     runtime: `Node.js/${process.version}`,
+///     storage: this.storage.constructor.name,
+///   },
+/// }, ...)
+/// ```
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EnableParams {
+    pub host: String,
+    pub port: u32,
+    pub version: String,
+    pub byoc: bool,
+    pub no_fast_enable: bool,
+    pub flavor: Flavor,
+}
+
+impl EnableParams {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            host: config.host_ip.clone(),
+            port: config.host_port,
+            version: PROTOCOL_VERSION.to_string(),
+            byoc: config.byoc,
+            no_fast_enable: config.no_fast_enable,
+            flavor: Flavor {
+                runtime: format!("Rust/{}", env!("CARGO_PKG_VERSION")),
+                storage: config.storage.as_str().to_string(),
+            },
+        }
+    }
+}
+
+/// 把 ack 的 `[err, data]` 拆开
+/// 兼容 `[err, data]` 和 `[[err, data]]` 两种包装
+pub fn parse_ack(payload: Payload) -> Result<Value, AckError> {
+    let mut value = match payload {
+        Payload::Text(values) => Value::Array(values),
+        other => return Err(AckError::BadPayload(format!("{:?}", other))),
+    };
+    // 剥掉多余的一层数组
+    loop {
+        match &value {
+            Value::Array(arr) if arr.len() == 1 && arr[0].is_array() => {
+                value = arr[0].clone();
+            }
+            _ => break,
+        }
+    }
+    let arr = match value {
+        Value::Array(arr) if !arr.is_empty() => arr,
+        other => return Err(AckError::BadPayload(format!("{:?}", other))),
+    };
+    match &arr[0] {
+        Value::Null | Value::Bool(false) => Ok(arr.get(1).cloned().unwrap_or(Value::Null)),
+        err => Err(AckError::Center(err.clone())),
+    }
+}
+
+/// 第 `attempt` 次重连前等待的时间
+/// 指数退避, 上限 [`RECONNECT_MAX_DELAY`], 再加上最多 1/4 的随机抖动
+pub fn reconnect_delay(attempt: u32) -> Duration {
+    let base = RECONNECT_BASE_DELAY
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(RECONNECT_MAX_DELAY);
+    let jitter_ms = base.as_millis() as u64 / 4;
+    let jitter = rand::thread_rng().gen_range(0..=jitter_ms);
+    base + Duration::from_millis(jitter)
 }
 
 #[derive(Clone)]
 pub struct Cluster {
     pub config: Config,
     pub ua: String,
-    pub socket: Client,
+    /// 当前的 socket, 重连之后会被替换
+    pub socket: Arc<RwLock<Client>>,
+    /// 当前 socket 是第几次连接的, 用来忽略旧 socket 的 disconnect
+    pub generation: Arc<AtomicU64>,
+    /// disconnect 时把对应的 generation 发给重连任务
+    disconnect_tx: mpsc::UnboundedSender<u64>,
+    /// 节点的生命周期状态
+    pub state: StateHandle,
+    /// 待上报的 hits 和 bytes
+    pub counters: Arc<Counters>,
+    /// 正在从 center 拉取的文件, 和下载处理函数共用
+    pub inflight: InFlight,
+    /// 当前文件列表的 hash -> path
+    pub files: FileIndex,
+    /// 缓存文件的存储, 和下载处理函数共用
+    pub storage: Arc<dyn Storage>,
+    /// 热点文件的内存缓存, 没有配置时为 None
+    pub memory: Option<Arc<MemoryCache>>,
+    /// 是否正在退出, 退出时的 disconnect 不算错误
+    pub shutting_down: Arc<AtomicBool>,
 }
 
 impl Cluster {
     pub async fn new(config: Config) -> Self {
-        let disconnect = |reason: Payload, _: Client| {
+        let ua = format!("openbmclapi-cluster/{}", PROTOCOL_VERSION);
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let state = StateHandle::new();
+        let (disconnect_tx, disconnect_rx) = mpsc::unbounded_channel();
+        let socket =
+            Self::connect_with_backoff(&config, 0, &disconnect_tx, &shutting_down, &state).await;
+        let storage = storage::from_config(&config);
+        let memory = MemoryCache::from_config(&config).map(Arc::new);
+        let cluster = Self {
+            config,
+            ua,
+            socket: Arc::new(RwLock::new(socket)),
+            generation: Arc::new(AtomicU64::new(0)),
+            disconnect_tx,
+            state,
+            counters: Arc::new(Counters::new()),
+            inflight: InFlight::new(),
+            files: FileIndex::new(),
+            storage,
+            memory,
+            shutting_down,
+        };
+        cluster.start_reconnect_supervisor(disconnect_rx);
+        cluster
+    }
+
+    /// 连接 center 的 socket.io
+    /// clusterId 和 clusterSecret 放在 query 里, 每次连接都会重新认证
+    async fn connect_socket(
+        config: &Config,
+        generation: u64,
+        disconnect_tx: &mpsc::UnboundedSender<u64>,
+        shutting_down: &Arc<AtomicBool>,
+    ) -> Result<Client, rust_socketio::Error> {
+        let disconnect_tx = disconnect_tx.clone();
+        let shutting_down = shutting_down.clone();
+        let disconnect = move |reason: Payload, _: Client| {
+            let disconnect_tx = disconnect_tx.clone();
+            let shutting_down = shutting_down.load(Ordering::SeqCst);
             async move {
-                fatal!("socket disconnect: {:?}", reason);
+                if shutting_down {
+                    info!("socket disconnected: {:?}", reason);
+                    return;
+                }
+                warn!("socket disconnect: {:?}", reason);
+                let _ = disconnect_tx.send(generation);
             }
             .boxed()
         };
-        let ua = format!("openbmclapi-cluster/{}", PROTOCOL_VERSION);
 
         // connect_url = f"{center}?clusterId={cluster_id}&clusterSecret={cluster_secret}"
         let url = format!(
@@ -45,27 +347,340 @@ impl Cluster {
             config.center_url.clone(), config.cluster_id, config.cluster_secret
         );
 
-        let socket = ClientBuilder::new(url.as_str())
+        ClientBuilder::new(url.as_str())
             .transport_type(TransportType::Websocket)
             .on("error", |err, _| async move {
-                println!("socket error {:?}", err)
+                warn!("socket error {:?}", err)
             }.boxed())
             .on("message", |msg, _| {
                 async move { debug!("socket message: {:?}", msg) }.boxed()
             })
-            .on("disconnect", disconnect)
+            .on("disconnect", disconnect.clone())
+            // 底层连接断开时 rust_socketio 触发的是 close
+            .on("close", disconnect)
             .connect()
             .await
-            .expect("Failed to connect to center");
-        info!("websocket connected");
-        Self { config, ua, socket }
     }
 
-    pub async fn disconnect(&self) {
-        self.socket
-            .disconnect()
+    /// 一直重试直到连上为止
+    async fn connect_with_backoff(
+        config: &Config,
+        generation: u64,
+        disconnect_tx: &mpsc::UnboundedSender<u64>,
+        shutting_down: &Arc<AtomicBool>,
+        state: &StateHandle,
+    ) -> Client {
+        let mut attempt = 0;
+        loop {
+            info!("connecting to center, attempt {}", attempt + 1);
+            let _ = state.transition(ClusterState::Connecting);
+            match Self::connect_socket(config, generation, disconnect_tx, shutting_down).await {
+                Ok(socket) => {
+                    info!("websocket connected");
+                    let _ = state.transition(ClusterState::Connected);
+                    return socket;
+                }
+                Err(err) => {
+                    let _ = state.transition(ClusterState::Disconnected);
+                    let delay = reconnect_delay(attempt);
+                    warn!("connect to center failed: {:?}, retry in {:?}", err, delay);
+                    tokio::time::sleep(delay).await;
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        }
+    }
+
+    /// 后台重连任务
+    /// 收到当前 socket 的 disconnect 之后带退避地重连,
+    /// 如果断开前是 enable 状态, 重连后一直重试 enable 直到成功或者退出
+    fn start_reconnect_supervisor(&self, mut disconnect_rx: mpsc::UnboundedReceiver<u64>) {
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            while let Some(generation) = disconnect_rx.recv().await {
+                if cluster.shutting_down.load(Ordering::SeqCst) {
+                    break;
+                }
+                if generation != cluster.generation.load(Ordering::SeqCst) {
+                    // 旧 socket 的 disconnect, 已经处理过了
+                    continue;
+                }
+                let was_enabled = matches!(
+                    cluster.state.transition(ClusterState::Disconnected),
+                    Ok(ClusterState::Enabled)
+                );
+                warn!("lost connection to center, reconnecting");
+                // 先切换 generation, 新 socket 刚连上就断开时它的 disconnect 也能被认出来
+                let next = generation + 1;
+                cluster.generation.store(next, Ordering::SeqCst);
+                let socket = Self::connect_with_backoff(
+                    &cluster.config,
+                    next,
+                    &cluster.disconnect_tx,
+                    &cluster.shutting_down,
+                    &cluster.state,
+                )
+                .await;
+                let old = std::mem::replace(&mut *cluster.socket.write().await, socket);
+                let _ = old.disconnect().await;
+                if cluster.shutting_down.load(Ordering::SeqCst) {
+                    cluster.disconnect().await;
+                    break;
+                }
+                if !was_enabled {
+                    continue;
+                }
+                // 在后台一直重试, 不耽误处理新 socket 的 disconnect
+                let cluster = cluster.clone();
+                tokio::spawn(async move { cluster.enable_with_retry().await });
+            }
+        });
+    }
+
+    /// emit 一个事件, 并等待 center 的 ack
+    /// 返回 ack 中的 data 部分
+    pub async fn emit_and_wait<D: Into<Payload>>(
+        &self,
+        event: &str,
+        data: D,
+        timeout: Duration,
+    ) -> Result<Value, AckError> {
+        let (tx, rx) = oneshot::channel::<Payload>();
+        let tx = Arc::new(Mutex::new(Some(tx)));
+        let ack_callback = move |message: Payload, _| {
+            let tx = tx.clone();
+            async move {
+                let tx = tx.lock().unwrap().take();
+                if let Some(tx) = tx {
+                    let _ = tx.send(message);
+                }
+            }
+            .boxed()
+        };
+        let socket = self.socket.read().await.clone();
+        socket
+            .emit_with_ack(event, data, timeout, ack_callback)
             .await
-            .expect("Failed to disconnect");
+            .map_err(|err| AckError::Emit(format!("{:?}", err)))?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(message)) => parse_ack(message),
+            _ => Err(AckError::Timeout),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.state.get() == ClusterState::Enabled
+    }
+
+    /// 如果还处在 `from` 这个中间状态, 回到 Connected
+    /// 期间如果已经断线, 就保持 Disconnected
+    fn leave_state(&self, from: ClusterState) {
+        if self.state.get() == from {
+            let _ = self.state.transition(ClusterState::Connected);
+        }
+    }
+
+    /// ```typescript
+    /// public async enable(): Promise<void> {
+    ///   await new Promise<void>((resolve, reject) => {
+    ///     this.socket?.emit('enable', {...}, ([err, ack]: [unknown, unknown]) => {
+    ///       if (err) return reject(err)
+    ///       if (ack !== true) return reject(ack)
+    ///       resolve()
+    ///     })
+    ///   })
+    ///   this.isEnabled = true
+    /// }
+    /// ```
+    pub async fn enable(&self) -> Result<(), AckError> {
+        self.state
+            .transition(ClusterState::Enabling)
+            .map_err(AckError::InvalidState)?;
+        let params = EnableParams::from_config(&self.config);
+        info!("enabling cluster: {:?}", params);
+        let data = serde_json::to_value(&params).unwrap();
+        let ack = self
+            .emit_and_wait("enable", data, Duration::from_secs(5 * 60))
+            .await;
+        match ack {
+            Ok(Value::Bool(true)) => {
+                let _ = self.state.transition(ClusterState::Enabled);
+                info!("cluster enabled");
+                Ok(())
+            }
+            Ok(other) => {
+                warn!("enable failed, center ack: {:?}", other);
+                self.leave_state(ClusterState::Enabling);
+                Err(AckError::Center(other))
+            }
+            Err(err) => {
+                warn!("enable failed: {:?}", err);
+                self.leave_state(ClusterState::Enabling);
+                Err(err)
+            }
+        }
+    }
+
+    /// 一直重试 enable, 间隔和重连一样指数退避
+    /// 开始退出, 或者不再是 Connected (断线了, 或者别处已经在 enable) 时停止
+    /// 返回最后是否处于 enable 状态
+    pub async fn enable_with_retry(&self) -> bool {
+        let mut attempt = 0;
+        loop {
+            if self.shutting_down.load(Ordering::SeqCst)
+                || self.state.get() != ClusterState::Connected
+            {
+                return self.is_enabled();
+            }
+            if self.enable().await.is_ok() {
+                return true;
+            }
+            let delay = reconnect_delay(attempt);
+            warn!("retry enable in {:?}", delay);
+            tokio::time::sleep(delay).await;
+            attempt = attempt.saturating_add(1);
+        }
+    }
+
+    /// ```typescript
+    /// public async disable(): Promise<void> {
+    ///   const [err, ack] = await this.socket.emitWithAck('disable', null)
+    ///   this.isEnabled = false
+    ///   if (err) throw err
+    ///   if (ack !== true) throw new Error('节点禁用失败')
+    /// }
+    /// ```
+    pub async fn disable(&self) -> Result<(), AckError> {
+        self.state
+            .transition(ClusterState::Disabling)
+            .map_err(AckError::InvalidState)?;
+        let ack = self
+            .emit_and_wait("disable", Value::Null, Duration::from_secs(30))
+            .await;
+        self.leave_state(ClusterState::Disabling);
+        match ack {
+            Ok(Value::Bool(true)) => {
+                info!("cluster disabled");
+                Ok(())
+            }
+            Ok(other) => {
+                warn!("disable failed, center ack: {:?}", other);
+                Err(AckError::Center(other))
+            }
+            Err(err) => {
+                warn!("disable failed: {:?}", err);
+                Err(err)
+            }
+        }
+    }
+
+    /// ```typescript
+    /// public async keepAlive(): Promise<boolean> {
+    ///   const counters = clone(this.counters)
+    ///   const [err, date] = await this.socket.emitWithAck('keep-alive', {
+    ///     time: new Date(),
+    ///     ...counters,
+    ///   })
+    ///   if (err) throw new Error('keep alive error', {cause: err})
+    ///   this.counters.hits -= counters.hits
+    ///   this.counters.bytes -= counters.bytes
+    ///   return !!date
+    /// }
+    /// ```
+    /// 返回 center 是否还认这个节点
+    pub async fn keep_alive(&self) -> Result<bool, AckError> {
+        let (hits, bytes) = self.counters.snapshot();
+        let data = serde_json::json!({
+            "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            "hits": hits,
+            "bytes": bytes,
+        });
+        let ack = self
+            .emit_and_wait("keep-alive", data, Duration::from_secs(10))
+            .await?;
+        self.counters.subtract(hits, bytes);
+        let alive = !matches!(ack, Value::Null | Value::Bool(false));
+        info!(
+            "keep-alive success, hits: {}, bytes: {}, status: {:?}, sign failures: {}, measure bytes: {}",
+            hits,
+            bytes,
+            self.counters.status_classes(),
+            self.counters.sign_failures(),
+            self.counters.measure_bytes()
+        );
+        if let Some(memory) = &self.memory {
+            let stats = memory.stats();
+            let (memory_hits, memory_misses) = self.counters.memory_lookups();
+            info!(
+                "memory cache hits: {}, misses: {}, entries: {}, bytes: {}",
+                memory_hits, memory_misses, stats.entries, stats.bytes
+            );
+        }
+        Ok(alive)
+    }
+
+    /// 后台 keep-alive 任务, 在第一次 enable 成功之后启动
+    /// 连续失败或者被 center 踢掉之后会 disable 再 enable,
+    /// 之后只要 socket 还连着但没有 enable (重新 enable 失败了), 就一直重试直到成功
+    pub fn start_keep_alive(&self) -> tokio::task::JoinHandle<()> {
+        let cluster = self.clone();
+        tokio::spawn(async move {
+            let mut failures = 0;
+            let mut interval = tokio::time::interval(KEEP_ALIVE_INTERVAL);
+            // 第一次 tick 是立即返回的
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                if cluster.shutting_down.load(Ordering::SeqCst) {
+                    break;
+                }
+                match cluster.state.get() {
+                    ClusterState::Enabled => (),
+                    ClusterState::Connected => {
+                        warn!("cluster is not enabled, re-enabling");
+                        if cluster.enable_with_retry().await {
+                            failures = 0;
+                        }
+                        continue;
+                    }
+                    // 正在重连或者切换状态, 由对应的流程负责
+                    _ => continue,
+                }
+                match cluster.keep_alive().await {
+                    Ok(true) => {
+                        failures = 0;
+                        continue;
+                    }
+                    Ok(false) => {
+                        warn!("kicked by center");
+                        failures = KEEP_ALIVE_MAX_FAILURES;
+                    }
+                    Err(err) => {
+                        failures += 1;
+                        warn!(
+                            "keep-alive failed ({}/{}): {:?}",
+                            failures, KEEP_ALIVE_MAX_FAILURES, err
+                        );
+                    }
+                }
+                if failures >= KEEP_ALIVE_MAX_FAILURES {
+                    warn!("too many keep-alive failures, re-enabling");
+                    let _ = cluster.disable().await;
+                    if cluster.enable_with_retry().await {
+                        failures = 0;
+                    }
+                }
+            }
+        })
+    }
+
+    pub async fn disconnect(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+        let _ = self.state.transition(ClusterState::Disconnected);
+        let socket = self.socket.read().await.clone();
+        if let Err(err) = socket.disconnect().await {
+            warn!("disconnect error: {:?}", err);
+        }
     }
 
     /// public async requestCert(): Promise<void> {
@@ -78,64 +693,24 @@ impl Cluster {
     ///   await fse.outputFile(join(this.tmpDir, 'cert.pem'), cert.cert)
     ///   await fse.outputFile(join(this.tmpDir, 'key.pem'), cert.key)
     /// }
-    pub async fn request_cert(&self) -> bool {
-        tokio::time::sleep(Duration::from_millis(200)).await;
-        let tmp_dir = self.config.cache_dir.clone();
-        let ack_callback = move |message: Payload, _| {
-            let tmp_dir = tmp_dir.clone();
-            async move {
-                match message {
-                    Payload::Text(values) => {
-                        if values.is_empty() {
-                            return;
-                        }
-                        if !values[0].is_array() || values[0].as_array().unwrap()[0].is_array(){
-                            return;
-                        }
-                        let data = &values[0][0][1];
-                        let cert = &data["cert"];
-                        let key = &data["key"];
-                        let cert_file = tmp_dir.clone().join("cert.pem");
-                        let key_file = tmp_dir.clone().join("key.pem");
-                        if cert_file.exists() {
-                            if tokio::fs::remove_file(cert_file.clone()).await.is_err() {
-                                warn!("remove cert file error");
-                            }
-                            
-                        } else {
-                            if let Some(parent) = cert_file.parent() {
-                                if !parent.exists() {
-                                    if tokio::fs::create_dir_all(parent).await.is_err() {
-                                        warn!("create cert file parent dir error");
-                                    }
-                                }
-                            }
-                        }
-                        if key_file.exists() {
-                            if tokio::fs::remove_file(key_file.clone()).await.is_err() {
-                                warn!("remove key file error");
-                            }
-                        }
-
-                        
-
-                    },
-                    _ => (),
-                }
-            }
-            .boxed()
-        };
-        let res = self
-            .socket
-            .emit_with_ack("request-cert", "", Duration::from_secs(10), ack_callback)
-            .await;
-        tokio::time::sleep(Duration::from_secs(5)).await;
-        if res.is_err() {
-            warn!("request cert error: {:?}", res.err());
-            false
-        } else {
-            true
+    pub async fn request_cert(&self) -> Result<CertPair, CertError> {
+        let data = self
+            .emit_and_wait("request-cert", "", Duration::from_secs(10))
+            .await
+            .map_err(CertError::Ack)?;
+        let cert: CertPair =
+            serde_json::from_value(data).map_err(|err| CertError::BadCert(format!("{:?}", err)))?;
+        cert.save(&self.config.cache_dir)
+            .await
+            .map_err(CertError::Io)?;
+        match cert.not_after() {
+            Ok(not_after) => info!("got cert from center, expires at {}", not_after),
+            Err(err) => warn!(
+                "got cert from center, but failed to parse expiry: {:?}",
+                err
+            ),
         }
+        Ok(cert)
     }
 
     /// ```typescript
@@ -159,6 +734,7 @@ impl Cluster {
     ///         {name: 'path', type: 'string'},
     ///         {name: 'hash', type: 'string'},
     ///         {name: 'size', type: 'long'},
+    ///         {name: 'mtime', type: 'long'},
     ///       ],
     ///     } as schema.RecordType,
     ///   })
@@ -172,6 +748,8 @@ impl Cluster {
     ///   }
     /// }
     /// ```
+    /// 之前获取过的话, 会带上 lastModified 只获取变化的部分,
+    /// center 返回 204/304 时直接使用本地的快照
     pub async fn get_file_list(&self) -> Option<Vec<SyncFile>> {
         // server: https://openbmclapi.bangbang93.com
         // path: /openbmclapi/files
@@ -183,21 +761,46 @@ impl Cluster {
             .user_agent(self.ua.clone())
             .build()
             .unwrap();
+        let now = chrono::Utc::now().timestamp_millis();
+        let snapshot = FileListSnapshot::load(&self.config.cache_dir)
+            .await
+            .filter(|snapshot| !snapshot.need_full_fetch(now));
         info!("getting file list from: {}", url);
-        let res = client
+        let mut req = client
             .get(url)
             .basic_auth(username, Some(password))
-            .timeout(std::time::Duration::from_secs(60))
-            .send()
-            .await;
+            .timeout(std::time::Duration::from_secs(60));
+        if let Some(snapshot) = &snapshot {
+            info!("last modified: {}", snapshot.last_modified);
+            req = req.query(&[("lastModified", snapshot.last_modified)]);
+            if let Some(last_modified) = &snapshot.last_modified_header {
+                req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        let res = req.send().await;
         if res.is_err() {
             warn!("get file list error: {:?}", res.err());
             return None;
         }
         let res = res.unwrap();
-        match res.status() {
-            StatusCode::OK => {
-                let body = res.bytes().await.unwrap();
+        match (res.status(), snapshot) {
+            (StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED, Some(snapshot)) => {
+                info!("file list not modified, {} files", snapshot.files.len());
+                Some(snapshot.files)
+            }
+            (StatusCode::OK, snapshot) => {
+                let last_modified_header = res
+                    .headers()
+                    .get(header::LAST_MODIFIED)
+                    .and_then(|value| value.to_str().ok())
+                    .map(|value| value.to_string());
+                let body = match res.bytes().await {
+                    Ok(body) => body,
+                    Err(err) => {
+                        warn!("read file list error: {:?}", err);
+                        return None;
+                    }
+                };
                 info!("got file list len: {}, decompressing", body.len());
                 let cur = std::io::Cursor::new(body);
                 let raw_data = decode_all(cur);
@@ -206,10 +809,31 @@ impl Cluster {
                     return None;
                 }
                 let raw_data = raw_data.unwrap();
-                avro_data_to_file_list(raw_data)
+                let files = avro_data_to_file_list(raw_data)?;
+
+                let mut snapshot = match snapshot {
+                    Some(mut snapshot) => {
+                        info!("merging {} changed files", files.len());
+                        snapshot.merge(files);
+                        snapshot
+                    }
+                    None => FileListSnapshot {
+                        full_fetched_at: now,
+                        files,
+                        ..Default::default()
+                    },
+                };
+                if let Some(mtime) = snapshot.max_mtime() {
+                    snapshot.last_modified = mtime;
+                }
+                snapshot.last_modified_header = last_modified_header;
+                if let Err(err) = snapshot.save(&self.config.cache_dir).await {
+                    warn!("save file list snapshot error: {:?}", err);
+                }
+                Some(snapshot.files)
             }
-            _ => {
-                warn!("faild to get file list, net status: {:?}", res.status());
+            (status, _) => {
+                warn!("faild to get file list, net status: {:?}", status);
                 None
             }
         }
@@ -244,6 +868,67 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_enable_params_flavor() {
+        let mut config = Config::new(
+            None,
+            "127.0.0.1".to_string(),
+            None,
+            "id".to_string(),
+            "secret".to_string(),
+            None,
+            None,
+            None,
+        );
+        assert_eq!(EnableParams::from_config(&config).flavor.storage, "file");
+        config.storage = crate::config::StorageKind::S3;
+        assert_eq!(EnableParams::from_config(&config).flavor.storage, "s3");
+    }
+
+    #[test]
+    fn test_parse_ack() {
+        let ok = Payload::Text(vec![serde_json::json!([[null, true]])]);
+        assert_eq!(parse_ack(ok).unwrap(), Value::Bool(true));
+        let flat = Payload::Text(vec![Value::Null, serde_json::json!({"a": 1})]);
+        assert_eq!(parse_ack(flat).unwrap(), serde_json::json!({"a": 1}));
+        let err = Payload::Text(vec![serde_json::json!([{"message": "no"}])]);
+        assert!(matches!(parse_ack(err), Err(AckError::Center(_))));
+        let empty = Payload::Text(vec![]);
+        assert!(matches!(parse_ack(empty), Err(AckError::BadPayload(_))));
+    }
+
+    #[test]
+    fn test_file_list_merge() {
+        let file = |path: &str, hash: &str| SyncFile {
+            path: path.to_string(),
+            hash: FileHash::parse(&hash.repeat(32)).unwrap(),
+            size: 1,
+            mtime: hash.parse().unwrap(),
+        };
+        let mut snapshot = FileListSnapshot {
+            files: vec![file("/a", "1"), file("/b", "2")],
+            ..Default::default()
+        };
+        snapshot.merge(vec![file("/b", "3"), file("/c", "4")]);
+        assert_eq!(
+            snapshot.files,
+            vec![file("/a", "1"), file("/b", "3"), file("/c", "4")]
+        );
+        assert_eq!(snapshot.max_mtime(), Some(4));
+        assert_eq!(FileListSnapshot::default().max_mtime(), None);
+        assert!(snapshot.need_full_fetch(FILE_LIST_FULL_FETCH_INTERVAL.as_millis() as i64 + 1));
+        assert!(!snapshot.need_full_fetch(0));
+    }
+
+    #[test]
+    fn test_reconnect_delay() {
+        assert!(reconnect_delay(0) >= RECONNECT_BASE_DELAY);
+        assert!(reconnect_delay(0) <= RECONNECT_BASE_DELAY * 5 / 4);
+        assert!(reconnect_delay(3) >= Duration::from_secs(8));
+        assert!(reconnect_delay(100) >= RECONNECT_MAX_DELAY);
+        assert!(reconnect_delay(100) <= RECONNECT_MAX_DELAY * 5 / 4);
+    }
+
     #[cfg(feature = "local_test")]
     #[tokio::test]
     async fn test_get_file_list() {
@@ -262,7 +947,7 @@ mod tests {
         crate::log::init_log_with_cli();
         let config = gen_config();
         let cluster = Cluster::new(config).await;
-        cluster.request_cert().await;
+        cluster.request_cert().await.unwrap();
         cluster.disconnect().await;
         ()
     }
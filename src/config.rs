@@ -8,7 +8,81 @@ use {
     tracing::{info, warn},
 };
 
-const CONFIG_PATH: &str = "config.toml";
+pub const CONFIG_PATH: &str = "config.toml";
+
+/// CLUSTER_STORAGE
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// 本地的 cache_dir
+    #[default]
+    File,
+    /// WebDAV, 下载请求会 302 到 WebDAV 上
+    Webdav,
+    /// S3 兼容的对象存储, 下载请求会 302 到预签名的地址
+    S3,
+}
+
+impl StorageKind {
+    /// 和 CLUSTER_STORAGE 的写法一致, 也是 enable 时上报的 flavor.storage
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::File => "file",
+            Self::Webdav => "webdav",
+            Self::S3 => "s3",
+        }
+    }
+}
+
+/// storage = "webdav" 时的 CLUSTER_STORAGE_OPTIONS
+/// 字段和 node 版的一致, 可以直接使用原来的 json
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WebdavConfig {
+    /// WebDAV 服务的地址, 例如 `http://127.0.0.1:5244/dav`
+    pub url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// 文件存放的目录
+    #[serde(default, alias = "basePath")]
+    pub base_path: String,
+    /// 302 跳转使用的地址, 比如 alist 的直链
+    #[serde(default, alias = "publicUrl")]
+    pub public_url: Option<String>,
+    /// 没有 public_url 时, 是否 302 到带账号密码的 url
+    /// 账号密码会暴露给所有客户端, 所以默认关闭, 由节点转发
+    #[serde(default, alias = "redirectWithCredentials")]
+    pub redirect_with_credentials: bool,
+}
+
+/// storage = "s3" 时的 CLUSTER_STORAGE_OPTIONS
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct S3Config {
+    /// 服务地址, 例如 `https://s3.amazonaws.com` 或者 `http://127.0.0.1:9000`
+    pub endpoint: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub bucket: String,
+    #[serde(alias = "accessKeyId")]
+    pub access_key_id: String,
+    #[serde(alias = "secretAccessKey")]
+    pub secret_access_key: String,
+    /// 对象 key 的前缀
+    #[serde(default)]
+    pub prefix: String,
+    /// 使用 `endpoint/bucket/key` 而不是 `bucket.endpoint/key`, MinIO 需要打开
+    #[serde(default, alias = "pathStyle")]
+    pub path_style: bool,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_memory_cache_max_object_mb() -> u64 {
+    32
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 // TODO: 将除了 cluster_id, cluster_secret 之外的配置项可选化
@@ -29,6 +103,46 @@ pub struct Config {
     pub no_open: bool,
     /// cache dir
     pub cache_dir: PathBuf,
+    /// NO_FAST_ENABLE
+    #[serde(default)]
+    pub no_fast_enable: bool,
+    /// CLUSTER_BYOC
+    /// 使用自己的证书 (或者由前端终止 TLS), 不向 center 申请证书
+    #[serde(default)]
+    pub byoc: bool,
+    /// SSL_CERT
+    /// byoc 时使用的证书, 不填则以 http 提供服务
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
+    /// SSL_KEY
+    /// byoc 时使用的私钥
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    /// CLUSTER_STORAGE
+    #[serde(default)]
+    pub storage: StorageKind,
+    /// CLUSTER_STORAGE_OPTIONS
+    #[serde(default)]
+    pub webdav: Option<WebdavConfig>,
+    /// CLUSTER_STORAGE_OPTIONS
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    /// MEMORY_CACHE_MB
+    /// 热点文件内存缓存的总大小, 单位 MiB, 0 为关闭
+    #[serde(default)]
+    pub memory_cache_mb: u64,
+    /// MEMORY_CACHE_MAX_OBJECT_MB
+    /// 超过这个大小的文件不放进内存缓存, 单位 MiB
+    #[serde(default = "default_memory_cache_max_object_mb")]
+    pub memory_cache_max_object_mb: u64,
+    /// CLUSTER_GC
+    /// 获取文件列表之后清理不在列表中的文件
+    #[serde(default)]
+    pub gc: bool,
+    /// CLUSTER_GC_TRASH_DAYS
+    /// 清理的文件先移到回收站保留的天数, 0 为直接删除
+    #[serde(default)]
+    pub gc_trash_days: u64,
 }
 
 impl Config {
@@ -59,19 +173,37 @@ impl Config {
             no_demaon: no_demaon.unwrap_or(false),
             cache_dir,
             no_open: no_open.unwrap_or(false),
+            no_fast_enable: false,
+            byoc: false,
+            cert_path: None,
+            key_path: None,
+            storage: StorageKind::File,
+            webdav: None,
+            s3: None,
+            memory_cache_mb: 0,
+            memory_cache_max_object_mb: default_memory_cache_max_object_mb(),
+            gc: false,
+            gc_trash_days: 0,
         }
     }
 
-    pub fn convert_from_env() {
+    /// 从环境变量读取配置, 并保存到 config.toml
+    pub fn convert_from_env() -> Self {
         // Load from env
         let center_url = env::var("CENTER_URL").ok();
         let host_ip = env::var("CLUSTER_IP").unwrap_or_else(|_| {
             fatal!("CLUSTER_IP is required");
         });
-        let host_port = env::var("CLUSTER_PORT").unwrap().parse::<u32>().ok();
-        let no_demaon = env::var("NO_DAEMON").unwrap().parse::<bool>().ok();
+        let host_port = env::var("CLUSTER_PORT")
+            .ok()
+            .and_then(|x| x.parse::<u32>().ok());
+        let no_demaon = env::var("NO_DAEMON")
+            .ok()
+            .and_then(|x| x.parse::<bool>().ok());
         let cache_dir = env::var("CACHE_DIR").ok().map(|x| PathBuf::from(x));
-        let no_open = env::var("NO_OPEN").unwrap().parse::<bool>().ok();
+        let no_open = env::var("NO_OPEN")
+            .ok()
+            .and_then(|x| x.parse::<bool>().ok());
 
         let cluster_id = env::var("CLUSTER_ID").unwrap_or_else(|_| {
             fatal!("CLUSTER_ID is required");
@@ -81,10 +213,32 @@ impl Config {
             fatal!("CLUSTER_SECRET is required");
         });
 
+        let byoc = env::var("CLUSTER_BYOC")
+            .ok()
+            .and_then(|x| x.parse::<bool>().ok());
+        let cert_path = env::var("SSL_CERT").ok().map(PathBuf::from);
+        let key_path = env::var("SSL_KEY").ok().map(PathBuf::from);
+        let storage = env::var("CLUSTER_STORAGE").ok().map(|x| {
+            serde_json::from_value::<StorageKind>(serde_json::Value::String(x.clone()))
+                .unwrap_or_else(|_| {
+                    fatal!("unknown CLUSTER_STORAGE: {}", x);
+                })
+        });
+        let storage_options = env::var("CLUSTER_STORAGE_OPTIONS").ok();
+        let memory_cache_mb = env::var("MEMORY_CACHE_MB")
+            .ok()
+            .and_then(|x| x.parse::<u64>().ok());
+        let memory_cache_max_object_mb = env::var("MEMORY_CACHE_MAX_OBJECT_MB")
+            .ok()
+            .and_then(|x| x.parse::<u64>().ok());
+        let gc = env::var("CLUSTER_GC")
+            .ok()
+            .and_then(|x| x.parse::<bool>().ok());
+        let gc_trash_days = env::var("CLUSTER_GC_TRASH_DAYS")
+            .ok()
+            .and_then(|x| x.parse::<u64>().ok());
+
         // Decrapated warning
-        if env::var("CLUSTER_BYOC").is_ok() {
-            warn!("CLUSTER_BYOC is deprecated, ignored");
-        }
         if env::var("DISABLE_ACCESS_LOG").is_ok() {
             warn!("DISABLE_ACCESS_LOG is deprecated, ignored");
         }
@@ -96,8 +250,12 @@ impl Config {
             // If you want to use Nginx, why would you choose this program?
         }
 
+        let no_fast_enable = env::var("NO_FAST_ENABLE")
+            .ok()
+            .and_then(|x| x.parse::<bool>().ok());
+
         // Create config
-        let config = Config::new(
+        let mut config = Config::new(
             center_url,
             host_ip,
             host_port,
@@ -107,9 +265,70 @@ impl Config {
             cache_dir,
             no_open,
         );
+        if let Some(no_fast_enable) = no_fast_enable {
+            config.no_fast_enable = no_fast_enable;
+        }
+        if let Some(byoc) = byoc {
+            config.byoc = byoc;
+        }
+        config.cert_path = cert_path;
+        config.key_path = key_path;
+        if let Some(storage) = storage {
+            config.storage = storage;
+        }
+        if let Some(memory_cache_mb) = memory_cache_mb {
+            config.memory_cache_mb = memory_cache_mb;
+        }
+        if let Some(memory_cache_max_object_mb) = memory_cache_max_object_mb {
+            config.memory_cache_max_object_mb = memory_cache_max_object_mb;
+        }
+        if let Some(gc) = gc {
+            config.gc = gc;
+        }
+        if let Some(gc_trash_days) = gc_trash_days {
+            config.gc_trash_days = gc_trash_days;
+        }
+        if let Some(options) = &storage_options {
+            let options: serde_json::Value = serde_json::from_str(options).unwrap_or_else(|err| {
+                fatal!(
+                    ("Failed to parse CLUSTER_STORAGE_OPTIONS: {}", err),
+                    ("{}", err)
+                );
+            });
+            let result = match config.storage {
+                StorageKind::File => Ok(()),
+                StorageKind::Webdav => {
+                    serde_json::from_value(options).map(|webdav| config.webdav = Some(webdav))
+                }
+                StorageKind::S3 => serde_json::from_value(options).map(|s3| config.s3 = Some(s3)),
+            };
+            if let Err(err) = result {
+                fatal!(
+                    ("Failed to parse CLUSTER_STORAGE_OPTIONS: {}", err),
+                    ("{}", err)
+                );
+            }
+        }
 
         // Save config
         config.save();
+        config
+    }
+
+    /// 有 config.toml 就从文件加载, 否则从环境变量读取
+    pub fn load() -> Self {
+        if Path::new(CONFIG_PATH).exists() {
+            let raw_data = fs::read_to_string(CONFIG_PATH).unwrap_or_else(|err| {
+                fatal!(("Failed to read config: {}", err), ("{}", err));
+            });
+            let config: Config = toml::from_str(&raw_data).unwrap_or_else(|err| {
+                fatal!(("Failed to load config: {}", err), ("{}", err));
+            });
+            info!("Config loaded from {}", CONFIG_PATH);
+            config
+        } else {
+            Config::convert_from_env()
+        }
     }
 
     /// 保存至文件
@@ -146,18 +365,38 @@ impl Config {
         self.no_demaon = raw_data.no_demaon;
         self.cache_dir = raw_data.cache_dir;
         self.no_open = raw_data.no_open;
+        self.no_fast_enable = raw_data.no_fast_enable;
+        self.byoc = raw_data.byoc;
+        self.cert_path = raw_data.cert_path;
+        self.key_path = raw_data.key_path;
+        self.storage = raw_data.storage;
+        self.webdav = raw_data.webdav;
+        self.s3 = raw_data.s3;
+        self.memory_cache_mb = raw_data.memory_cache_mb;
+        self.memory_cache_max_object_mb = raw_data.memory_cache_max_object_mb;
+        self.gc = raw_data.gc;
+        self.gc_trash_days = raw_data.gc_trash_days;
         info!("Config loaded from {}", path);
     }
 
     pub fn join_center_url(&self, path: &str) -> String {
         format!("{}{}", self.center_url, path)
     }
+
+    /// 从 center 下载文件的地址
+    pub fn download_url(&self, hash: &str) -> String {
+        let mut url = self.join_center_url(&format!("/openbmclapi/download/{}", hash));
+        if self.no_open {
+            url.push_str("?noopen=1");
+        }
+        url
+    }
 }
 
 #[test]
 fn test_save_and_load_config() {
     let tmp_file = Path::new("tmp.toml");
-    let config: Config = Config::new(
+    let mut config: Config = Config::new(
         Some("https://example.com".to_string()),
         "0.0.0.0".to_string(),
         Some(23333),
@@ -177,6 +416,17 @@ fn test_save_and_load_config() {
         None,
         None,
     );
+    config.byoc = true;
+    config.cert_path = Some(PathBuf::from("cert.pem"));
+    config.key_path = Some(PathBuf::from("key.pem"));
+    config.storage = StorageKind::Webdav;
+    config.memory_cache_mb = 512;
+    config.gc = true;
+    config.gc_trash_days = 7;
+    config.webdav = serde_json::from_str(
+        r#"{"url": "http://127.0.0.1:5244/dav", "username": "admin", "basePath": "/bmclapi"}"#,
+    )
+    .unwrap();
     config.save_to_file(tmp_file.to_str().unwrap());
     test_config.update_from_file(tmp_file.to_str().unwrap());
     assert_eq!(test_config.center_url, "https://example.com");
@@ -187,6 +437,19 @@ fn test_save_and_load_config() {
     assert_eq!(test_config.no_demaon, true);
     assert_eq!(test_config.cache_dir, PathBuf::from("cache"));
     assert_eq!(test_config.no_open, true);
+    assert_eq!(test_config.byoc, true);
+    assert_eq!(test_config.cert_path, Some(PathBuf::from("cert.pem")));
+    assert_eq!(test_config.key_path, Some(PathBuf::from("key.pem")));
+    assert_eq!(test_config.storage, StorageKind::Webdav);
+    assert_eq!(test_config.memory_cache_mb, 512);
+    assert_eq!(test_config.memory_cache_max_object_mb, 32);
+    assert_eq!(test_config.gc, true);
+    assert_eq!(test_config.gc_trash_days, 7);
+    let webdav = test_config.webdav.unwrap();
+    assert_eq!(webdav.url, "http://127.0.0.1:5244/dav");
+    assert_eq!(webdav.username.as_deref(), Some("admin"));
+    assert_eq!(webdav.password, None);
+    assert_eq!(webdav.base_path, "/bmclapi");
 
     // Clean up the temporary config file
     fs::remove_file(tmp_file).unwrap();
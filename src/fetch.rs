@@ -0,0 +1,302 @@
+use crate::inflight::FlightGuard;
+use crate::serve::{insert_file_headers, AppState};
+use crate::stats::count_bytes;
+use crate::storage::{FileReader, Storage};
+use crate::utils::{FileHash, FileHasher};
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use futures_util::{stream, Stream, StreamExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_util::io::StreamReader;
+use tracing::{info, warn};
+
+/// 写入存储的缓冲区, 以 chunk 为单位
+const FETCH_CHANNEL_SIZE: usize = 16;
+/// 转发给客户端的缓冲区, 以 chunk 为单位
+/// 客户端落后这么多之后就不再转发给它, 避免拖慢写入缓存和等待同一个文件的其他请求
+const CLIENT_CHANNEL_SIZE: usize = 64;
+/// 上游多久没有响应或者没有新数据就放弃
+/// 不限制总时长, 大文件只要一直在传输就不会超时
+pub const FETCH_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// 缓存中没有的文件, 直接从 center 拉取
+/// 一边转发给客户端一边流式写入存储, 校验通过之后才提交
+/// 结束后通过 guard 通知等待同一个文件的其他请求
+pub async fn serve_from_center(
+    state: &AppState,
+    hash: &FileHash,
+    req_name: Option<&String>,
+    guard: FlightGuard,
+) -> Response {
+    let req = state
+        .client
+        .get(state.config.download_url(hash.as_str()))
+        .basic_auth(
+            state.config.cluster_id.clone(),
+            Some(state.config.cluster_secret.clone()),
+        )
+        .send();
+    let res = match tokio::time::timeout(FETCH_IDLE_TIMEOUT, req).await {
+        Ok(Ok(res)) if res.status().is_success() => res,
+        // 只有 center 明确说没有这个文件时才返回 404
+        Ok(Ok(res)) if res.status() == reqwest::StatusCode::NOT_FOUND => {
+            warn!("fetch {} from center failed, not found", hash);
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Ok(Ok(res)) => {
+            warn!(
+                "fetch {} from center failed, net status: {:?}",
+                hash,
+                res.status()
+            );
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+        Ok(Err(err)) => {
+            warn!("fetch {} from center failed: {:?}", hash, err);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+        Err(_) => {
+            warn!("fetch {} from center timed out", hash);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    info!("fetching {} from center", hash);
+    let len = res.content_length();
+    let body = tee_to_cache(
+        res.bytes_stream(),
+        len,
+        state.storage.clone(),
+        hash.clone(),
+        guard,
+    );
+
+    let mut builder = Response::builder().status(StatusCode::OK);
+    {
+        let header = builder.headers_mut().unwrap();
+        insert_file_headers(header, state, hash.as_str(), req_name);
+        if let Some(len) = len {
+            header.insert(header::CONTENT_LENGTH, len.into());
+        }
+    }
+    state.counters.hit();
+    let body = count_bytes(state.counters.clone(), body);
+    builder.body(Body::from_stream(body)).unwrap()
+}
+
+/// 边读边校验上游的数据流
+/// 最后一块数据要等校验通过之后才放出来, 校验失败, 上游出错,
+/// 或者超过 [`FETCH_IDLE_TIMEOUT`] 没有新数据时以错误结束
+pub fn verify_stream<S, E>(
+    upstream: S,
+    hash: FileHash,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: std::fmt::Debug + Send + 'static,
+{
+    let hasher = FileHasher::new(hash.as_str());
+    let state = (Box::pin(upstream), hash, hasher, None::<Bytes>);
+    stream::unfold(Some(state), |state| async move {
+        let (mut upstream, hash, mut hasher, mut pending) = state?;
+        let err = loop {
+            match tokio::time::timeout(FETCH_IDLE_TIMEOUT, upstream.next()).await {
+                Ok(Some(Ok(chunk))) => {
+                    hasher.update(&chunk);
+                    if let Some(prev) = pending.replace(chunk) {
+                        return Some((Ok(prev), Some((upstream, hash, hasher, pending))));
+                    }
+                }
+                Ok(None) if hasher.matches(hash.as_str()) => {
+                    return pending.map(|last| (Ok(last), None));
+                }
+                Ok(None) => {
+                    break std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("fetched {} failed to validate", hash),
+                    )
+                }
+                Ok(Some(Err(err))) => {
+                    break std::io::Error::other(format!("fetch {} interrupted: {:?}", hash, err))
+                }
+                Err(_) => {
+                    break std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!(
+                            "fetch {} timed out, no data in {:?}",
+                            hash, FETCH_IDLE_TIMEOUT
+                        ),
+                    )
+                }
+            }
+        };
+        Some((Err(err), None))
+    })
+}
+
+/// 把上游的数据流同时写到存储和返回给客户端的 body, 经过 [`verify_stream`] 校验
+/// 写入缓存不等待客户端: 客户端中途断开, 或者落后超过 [`CLIENT_CHANNEL_SIZE`] 之后就不再转发给它,
+/// 落后的客户端读完缓冲区之后 body 以错误结束
+/// 校验失败或者上游提前断开时两边都以错误结束,
+/// 这样客户端不会把损坏的文件当成完整的响应, 远程存储也收不到完整的 body, 不会提交这次写入
+fn tee_to_cache<S, E>(
+    upstream: S,
+    len: Option<u64>,
+    storage: Arc<dyn Storage>,
+    hash: FileHash,
+    guard: FlightGuard,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    E: std::fmt::Debug + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<Result<Bytes, std::io::Error>>(CLIENT_CHANNEL_SIZE);
+    tokio::spawn(async move {
+        // 一边拉取一边写入存储, 不在内存里攒整个文件
+        let (store_tx, store_rx) = mpsc::channel(FETCH_CHANNEL_SIZE);
+        let write = {
+            let hash = hash.clone();
+            let reader: FileReader = Box::pin(StreamReader::new(receiver_stream(store_rx)));
+            tokio::spawn(async move { storage.write_stream(&hash, reader, len).await })
+        };
+
+        let mut upstream = Box::pin(verify_stream(upstream, hash.clone()));
+        let mut lagging = false;
+        let mut failed = None;
+        while let Some(chunk) = upstream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    failed = Some(err);
+                    break;
+                }
+            };
+            // 写入失败的话就只转发给客户端
+            let _ = store_tx.send(Ok(chunk.clone())).await;
+            // 客户端已经断开的话就只写缓存
+            if !lagging && matches!(tx.try_send(Ok(chunk)), Err(TrySendError::Full(_))) {
+                warn!("client is too slow, stop forwarding {}", hash);
+                lagging = true;
+            }
+        }
+        if let Some(err) = &failed {
+            let _ = store_tx
+                .send(Err(std::io::Error::new(err.kind(), err.to_string())))
+                .await;
+        }
+        drop(store_tx);
+
+        let written = write
+            .await
+            .unwrap_or_else(|err| Err(std::io::Error::other(err)));
+        let committed = if let Some(err) = &failed {
+            warn!("{}, discarded", err);
+            false
+        } else if let Err(err) = written {
+            warn!("write {} to storage failed: {:?}", hash, err);
+            false
+        } else {
+            info!("fetched {} into cache", hash);
+            true
+        };
+        guard.finish(committed);
+
+        // 缓存已经处理完了, 剩下的可以慢慢等客户端读
+        let failed = match failed {
+            None if lagging => Some(std::io::Error::other(format!(
+                "client is too slow, {} truncated",
+                hash
+            ))),
+            failed => failed,
+        };
+        if let Some(err) = failed {
+            let _ = tx.send(Err(err)).await;
+        }
+    });
+    receiver_stream(rx)
+}
+
+fn receiver_stream(
+    rx: mpsc::Receiver<Result<Bytes, std::io::Error>>,
+) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Send + 'static {
+    stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    })
+}
+
+#[tokio::test]
+async fn test_tee_to_remote_storage() {
+    use crate::inflight::{Flight, InFlight};
+    use crate::storage::{spawn_fake_s3, spawn_fake_webdav, S3Storage, WebdavStorage};
+
+    let (_, dav_config) = spawn_fake_webdav().await;
+    let (_, s3_config) = spawn_fake_s3().await;
+    let storages: Vec<Arc<dyn Storage>> = vec![
+        Arc::new(WebdavStorage::new(&dav_config).unwrap()),
+        Arc::new(S3Storage::new(&s3_config).unwrap()),
+    ];
+    let hash = FileHash::parse("5d41402abc4b2a76b9719d911017c592").unwrap();
+    let inflight = InFlight::new();
+    for storage in storages {
+        // 长度正确但内容损坏的 body 不能被远程存储提交
+        for (chunks, ok) in [([&b"hel"[..], b"lx"], false), ([&b"hel"[..], b"lo"], true)] {
+            let Flight::Leader(guard) = inflight.join(hash.as_str()) else {
+                panic!("should lead");
+            };
+            let Flight::Follower(follower) = inflight.join(hash.as_str()) else {
+                panic!("should follow");
+            };
+            let upstream = stream::iter(chunks.map(|chunk| Ok::<_, ()>(Bytes::from_static(chunk))));
+            let body: Vec<_> =
+                tee_to_cache(upstream, Some(5), storage.clone(), hash.clone(), guard)
+                    .collect()
+                    .await;
+            assert_eq!(body.iter().all(|chunk| chunk.is_ok()), ok);
+            assert_eq!(follower.wait().await, ok);
+            assert_eq!(storage.size(&hash).await.unwrap(), ok.then_some(5));
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_tee_to_slow_client() {
+    use crate::inflight::{Flight, InFlight};
+    use md5::Digest;
+
+    let root = std::env::temp_dir().join("openbmclapi_rs_test_tee_to_slow_client");
+    let _ = tokio::fs::remove_dir_all(&root).await;
+    let storage: Arc<dyn Storage> = Arc::new(crate::storage::LocalStorage::new(root.clone()));
+    let data = vec![b'x'; CLIENT_CHANNEL_SIZE * 4];
+    let hash = FileHash::parse(&format!("{:x}", md5::Md5::digest(&data))).unwrap();
+    let inflight = InFlight::new();
+    let Flight::Leader(guard) = inflight.join(hash.as_str()) else {
+        panic!("should lead");
+    };
+    let Flight::Follower(follower) = inflight.join(hash.as_str()) else {
+        panic!("should follow");
+    };
+    let upstream = stream::iter(data.clone()).map(|byte| Ok::<_, ()>(Bytes::from(vec![byte])));
+    let body = tee_to_cache(
+        upstream,
+        Some(data.len() as u64),
+        storage.clone(),
+        hash.clone(),
+        guard,
+    );
+
+    // 客户端一直不读也不会拖住写入缓存
+    assert!(follower.wait().await);
+    assert_eq!(storage.size(&hash).await.unwrap(), Some(data.len() as u64));
+    // 跟不上的客户端收到的 body 以错误结束
+    let body: Vec<_> = body.collect().await;
+    assert!(body.len() < data.len());
+    assert!(body.last().unwrap().is_err());
+
+    tokio::fs::remove_dir_all(&root).await.unwrap();
+}
@@ -0,0 +1,178 @@
+use crate::cluster::{Cluster, SyncFile};
+use crate::storage::{Storage, StoredFile};
+use crate::utils::FileHash;
+
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+
+use tracing::{info, warn};
+
+/// 最近修改过的文件不清理, 可能是正在写入的临时文件
+pub const GC_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+/// 文件列表不到存储中文件数的 1/N 时不清理, 多半是 center 返回的列表不完整
+const GC_MIN_LIST_DIVISOR: usize = 2;
+
+/// 一次清理的结果
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GcSummary {
+    /// 删除或者移到回收站的文件数
+    pub removed: usize,
+    /// 删除或者移到回收站的字节数
+    pub bytes: u64,
+    /// 回收站中过期被删除的字节数
+    pub purged: u64,
+}
+
+/// 找出要清理的文件: 不在文件列表中的 hash, 临时文件和不是 hash 命名的文件
+/// `now - grace` 之后修改过的文件会被跳过
+pub fn garbage_files(
+    stored: Vec<StoredFile>,
+    files: &[SyncFile],
+    now: SystemTime,
+    grace: Duration,
+) -> Vec<StoredFile> {
+    let keep: HashSet<&FileHash> = files.iter().map(|file| &file.hash).collect();
+    stored
+        .into_iter()
+        .filter(|file| !file.hash.as_ref().is_some_and(|hash| keep.contains(hash)))
+        .filter(|file| match file.modified {
+            Some(modified) => now.duration_since(modified).unwrap_or_default() >= grace,
+            None => true,
+        })
+        .collect()
+}
+
+/// 清理存储中不在文件列表里的文件
+/// trash 不为 None 时先移到回收站, 并删除回收站中超过 trash 时长的文件
+/// 文件列表为空或者比存储中的文件少太多时不清理, 返回 InvalidData
+pub async fn collect_garbage(
+    storage: &dyn Storage,
+    files: &[SyncFile],
+    trash: Option<Duration>,
+    grace: Duration,
+) -> Result<(GcSummary, Vec<FileHash>), std::io::Error> {
+    let now = SystemTime::now();
+    let stored = storage.list().await?;
+    let stored_hashes = stored.iter().filter(|file| file.hash.is_some()).count();
+    if files.is_empty() || files.len() < stored_hashes / GC_MIN_LIST_DIVISOR {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "file list has {} files but storage has {}, skipped",
+                files.len(),
+                stored_hashes
+            ),
+        ));
+    }
+    let garbage = garbage_files(stored, files, now, grace);
+    let mut summary = GcSummary::default();
+    let mut removed_hashes = Vec::new();
+    for file in garbage {
+        let res = match trash {
+            Some(_) => storage.trash_key(&file.key).await,
+            None => storage.delete_key(&file.key).await,
+        };
+        match res {
+            Ok(()) => {
+                summary.removed += 1;
+                summary.bytes += file.size;
+                removed_hashes.extend(file.hash);
+            }
+            Err(err) => warn!("gc remove {} failed: {:?}", file.key, err),
+        }
+    }
+    if let Some(retention) = trash {
+        let before = now.checked_sub(retention).unwrap_or(SystemTime::UNIX_EPOCH);
+        match storage.purge_trash(before).await {
+            Ok(purged) => summary.purged = purged,
+            Err(err) => warn!("gc purge trash failed: {:?}", err),
+        }
+    }
+    Ok((summary, removed_hashes))
+}
+
+impl Cluster {
+    /// 获取文件列表之后调用, 清理 center 已经不再需要的文件
+    pub async fn gc(&self, files: &[SyncFile]) -> Option<GcSummary> {
+        let trash = match self.config.gc_trash_days {
+            0 => None,
+            days => Some(Duration::from_secs(days * 24 * 60 * 60)),
+        };
+        info!("gc started, {} files in list", files.len());
+        let (summary, removed) =
+            match collect_garbage(self.storage.as_ref(), files, trash, GC_GRACE_PERIOD).await {
+                Ok(res) => res,
+                Err(err) => {
+                    warn!("gc failed: {:?}", err);
+                    return None;
+                }
+            };
+        if let Some(memory) = &self.memory {
+            removed.iter().for_each(|hash| memory.remove(hash));
+        }
+        info!(
+            "gc finished, removed: {}, bytes: {}, purged from trash: {}",
+            summary.removed, summary.bytes, summary.purged
+        );
+        Some(summary)
+    }
+}
+
+#[tokio::test]
+async fn test_collect_garbage() {
+    use crate::storage::LocalStorage;
+
+    let root = std::env::temp_dir().join("openbmclapi_rs_test_collect_garbage");
+    let _ = tokio::fs::remove_dir_all(&root).await;
+    let storage = LocalStorage::new(root.clone());
+    let keep = FileHash::parse("5d41402abc4b2a76b9719d911017c592").unwrap();
+    let retired = FileHash::parse("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d").unwrap();
+    storage.write(&keep, b"hello").await.unwrap();
+    storage.write(&retired, b"hello!").await.unwrap();
+    tokio::fs::write(root.join("5d/5d41402a.1234abcd.tmp"), b"tmp")
+        .await
+        .unwrap();
+    tokio::fs::write(root.join("cert.pem"), b"cert")
+        .await
+        .unwrap();
+    let files = vec![SyncFile {
+        path: "/keep".to_string(),
+        hash: keep.clone(),
+        size: 5,
+        mtime: 0,
+    }];
+
+    // 文件列表为空时什么都不删
+    assert!(collect_garbage(&storage, &[], None, Duration::ZERO)
+        .await
+        .is_err());
+    assert_eq!(storage.list().await.unwrap().len(), 3);
+
+    // 刚写入的文件在 grace 期内不会被清理
+    let (summary, _) = collect_garbage(&storage, &files, None, GC_GRACE_PERIOD)
+        .await
+        .unwrap();
+    assert_eq!(summary, GcSummary::default());
+
+    let trash = Some(Duration::from_secs(24 * 60 * 60));
+    let (summary, removed) = collect_garbage(&storage, &files, trash, Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!(summary.removed, 2);
+    assert_eq!(summary.bytes, 9);
+    assert_eq!(removed, vec![retired.clone()]);
+    assert_eq!(storage.size(&keep).await.unwrap(), Some(5));
+    assert_eq!(storage.size(&retired).await.unwrap(), None);
+    assert!(root.join("cert.pem").exists());
+    // 还在回收站里
+    assert!(root.join(".trash").exists());
+
+    // 回收站保留时间为 0 时立刻清空
+    let (summary, _) = collect_garbage(&storage, &files, Some(Duration::ZERO), Duration::ZERO)
+        .await
+        .unwrap();
+    assert_eq!((summary.removed, summary.purged), (0, 9));
+    assert_eq!(storage.list().await.unwrap().len(), 1);
+
+    tokio::fs::remove_dir_all(&root).await.unwrap();
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+/// 正在从 center 拉取的文件
+/// 同一个 hash 同时只会拉取一次, 其他请求等待它完成
+/// 下载处理函数和同步共用一个
+#[derive(Clone, Default)]
+pub struct InFlight {
+    inner: Arc<Mutex<HashMap<String, watch::Receiver<Option<bool>>>>>,
+}
+
+pub enum Flight {
+    /// 由自己负责拉取, 完成后调用 [`FlightGuard::finish`]
+    Leader(FlightGuard),
+    /// 已经有人在拉取了, 等待它的结果
+    Follower(Follower),
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn join(&self, hash: &str) -> Flight {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(rx) = inner.get(hash) {
+            return Flight::Follower(Follower { rx: rx.clone() });
+        }
+        let (tx, rx) = watch::channel(None);
+        inner.insert(hash.to_string(), rx);
+        Flight::Leader(FlightGuard {
+            hash: hash.to_string(),
+            registry: self.clone(),
+            tx,
+            finished: false,
+        })
+    }
+}
+
+/// 拉取者持有的凭证
+/// 没有调用 finish 就被 drop 时视为失败
+pub struct FlightGuard {
+    hash: String,
+    registry: InFlight,
+    tx: watch::Sender<Option<bool>>,
+    finished: bool,
+}
+
+impl FlightGuard {
+    /// 通知等待者拉取的结果, ok 表示文件已经在缓存中
+    pub fn finish(mut self, ok: bool) {
+        self.complete(ok);
+    }
+
+    fn complete(&mut self, ok: bool) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        self.registry.inner.lock().unwrap().remove(&self.hash);
+        let _ = self.tx.send(Some(ok));
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.complete(false);
+    }
+}
+
+pub struct Follower {
+    rx: watch::Receiver<Option<bool>>,
+}
+
+impl Follower {
+    /// 等待拉取完成, 返回文件是否已经在缓存中
+    pub async fn wait(mut self) -> bool {
+        match self.rx.wait_for(|result| result.is_some()).await {
+            Ok(result) => result.unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_in_flight() {
+    let inflight = InFlight::new();
+    let Flight::Leader(guard) = inflight.join("a") else {
+        panic!("first join should lead");
+    };
+    let Flight::Follower(follower) = inflight.join("a") else {
+        panic!("second join should follow");
+    };
+    assert!(matches!(inflight.join("b"), Flight::Leader(_)));
+    let waiter = tokio::spawn(follower.wait());
+    guard.finish(true);
+    assert!(waiter.await.unwrap());
+
+    // 没有 finish 就 drop 视为失败
+    let Flight::Leader(guard) = inflight.join("a") else {
+        panic!("join after finish should lead");
+    };
+    let Flight::Follower(follower) = inflight.join("a") else {
+        panic!("second join should follow");
+    };
+    drop(guard);
+    assert!(!follower.wait().await);
+}
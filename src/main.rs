@@ -1,11 +1,92 @@
 mod cluster;
 mod config;
+mod fetch;
+mod gc;
+mod inflight;
 mod log;
+mod memcache;
 mod serve;
+mod shutdown;
+mod state;
+mod stats;
+mod storage;
+mod sync;
+mod tls;
 mod utils;
 
+use crate::cluster::Cluster;
+use crate::config::Config;
+
+use std::net::SocketAddr;
+
+use axum_server::Handle;
+use tracing::{info, warn};
+
 pub const PROTOCOL_VERSION: &str = "1.7.3";
 
-fn main() {
+#[tokio::main]
+async fn main() {
     log::init_log_with_cli();
+
+    let config = Config::load();
+    // CLUSTER_IP 是 center 访问节点用的公网地址, 可能是域名或者 NAT 外面的 IP, 不能拿来监听
+    let port = u16::try_from(config.host_port).unwrap_or_else(|_| {
+        fatal!("invalid port: {}", config.host_port);
+    });
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+
+    let cluster = Cluster::new(config).await;
+
+    match cluster.get_file_list().await {
+        Some(files) => {
+            if cluster.config.gc {
+                cluster.gc(&files).await;
+            }
+            cluster.sync_files(&files).await;
+        }
+        None => warn!("get file list failed, skip sync"),
+    }
+
+    let cert = cluster.obtain_cert().await.unwrap_or_else(|err| {
+        fatal!("failed to get cert: {:?}", err);
+    });
+
+    let app = serve::router(serve::AppState::from_cluster(&cluster));
+    let handle = Handle::new();
+    let shutdown = tokio::spawn(shutdown::graceful_shutdown(cluster.clone(), handle.clone()));
+
+    let server = match cert {
+        Some(cert) => {
+            let tls = cert.rustls_config().await.unwrap_or_else(|err| {
+                fatal!("invalid cert: {:?}", err);
+            });
+            if !cluster.config.byoc {
+                cluster.start_cert_renewal(tls.clone(), cert);
+            }
+            info!("serving https on {}", addr);
+            tokio::spawn(tls::serve_https(addr, app, tls, handle.clone()))
+        }
+        None => {
+            info!("serving http on {}", addr);
+            let server = axum_server::bind(addr)
+                .handle(handle.clone())
+                .serve(app.into_make_service());
+            tokio::spawn(server)
+        }
+    };
+
+    if handle.listening().await.is_none() {
+        fatal!("failed to listen on {}", addr);
+    }
+    if let Err(err) = cluster.enable().await {
+        fatal!("failed to enable cluster: {:?}", err);
+    }
+    cluster.start_keep_alive();
+
+    match server.await {
+        Ok(Ok(())) => info!("server stopped"),
+        Ok(Err(err)) => warn!("server error: {:?}", err),
+        Err(err) => warn!("server task error: {:?}", err),
+    }
+    let _ = shutdown.await;
 }
@@ -0,0 +1,194 @@
+use crate::config::Config;
+use crate::utils::FileHash;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+
+use axum::body::Bytes;
+
+const MIB: u64 = 1024 * 1024;
+/// 最多记住多少个只请求过一次的文件, 超过之后全部忘掉重新记
+const MAX_SEEN: usize = 4096;
+
+/// 热点文件的内存缓存, 按照最近最少使用淘汰
+/// 命中时直接返回 `Bytes`, 不需要读盘
+/// 第二次请求同一个文件时才由 [`MemoryCache::admit`] 放行, 在后台读进来
+#[derive(Debug)]
+pub struct MemoryCache {
+    inner: Mutex<Lru>,
+    /// 所有文件加起来的上限
+    max_bytes: u64,
+    /// 单个文件的上限
+    max_object: u64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    /// hash -> (数据, 最后一次访问的序号)
+    entries: HashMap<FileHash, (Bytes, u64)>,
+    /// 访问序号 -> hash, 第一个就是最久没用过的
+    order: BTreeMap<u64, FileHash>,
+    bytes: u64,
+    tick: u64,
+    /// 没命中过一次的文件
+    seen: HashSet<FileHash>,
+    /// 正在后台读进内存的文件
+    filling: HashSet<FileHash>,
+}
+
+impl Lru {
+    fn touch(&mut self, hash: &FileHash) -> Option<Bytes> {
+        self.tick += 1;
+        let (data, last) = self.entries.get_mut(hash)?;
+        self.order.remove(last);
+        *last = self.tick;
+        self.order.insert(self.tick, hash.clone());
+        Some(data.clone())
+    }
+
+    fn remove(&mut self, hash: &FileHash) {
+        if let Some((data, last)) = self.entries.remove(hash) {
+            self.order.remove(&last);
+            self.bytes -= data.len() as u64;
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((_, hash)) = self.order.pop_first() {
+            if let Some((data, _)) = self.entries.remove(&hash) {
+                self.bytes -= data.len() as u64;
+            }
+        }
+    }
+}
+
+/// [`MemoryCache::stats`] 的结果, 命中率在 [`crate::stats::Counters`] 里
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryCacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+impl MemoryCache {
+    pub fn new(max_bytes: u64, max_object: u64) -> Self {
+        Self {
+            inner: Mutex::new(Lru::default()),
+            max_bytes,
+            max_object: max_object.min(max_bytes),
+        }
+    }
+
+    /// memory_cache_mb 为 0 时不启用
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if config.memory_cache_mb == 0 {
+            return None;
+        }
+        Some(Self::new(
+            config.memory_cache_mb * MIB,
+            config.memory_cache_max_object_mb * MIB,
+        ))
+    }
+
+    /// 这个大小的文件是否会被缓存
+    pub fn accepts(&self, size: u64) -> bool {
+        size <= self.max_object
+    }
+
+    pub fn get(&self, hash: &FileHash) -> Option<Bytes> {
+        self.inner.lock().unwrap().touch(hash)
+    }
+
+    /// 没命中之后调用, 是否应该把这个文件读进内存
+    /// 第一次没命中只记下来, 第二次才放行; 放行之后到 [`MemoryCache::end_fill`] 之前不会再放行,
+    /// 同一个文件同时只会读一次
+    pub fn admit(&self, hash: &FileHash, size: u64) -> bool {
+        if !self.accepts(size) {
+            return false;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.contains_key(hash) || inner.filling.contains(hash) {
+            return false;
+        }
+        if inner.seen.remove(hash) {
+            inner.filling.insert(hash.clone());
+            return true;
+        }
+        if inner.seen.len() >= MAX_SEEN {
+            inner.seen.clear();
+        }
+        inner.seen.insert(hash.clone());
+        false
+    }
+
+    /// 后台读取结束, 不管有没有成功放进缓存
+    pub fn end_fill(&self, hash: &FileHash) {
+        self.inner.lock().unwrap().filling.remove(hash);
+    }
+
+    /// 放进缓存, 超过单个文件上限的直接忽略
+    pub fn insert(&self, hash: &FileHash, data: Bytes) {
+        let size = data.len() as u64;
+        if !self.accepts(size) {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(hash);
+        while inner.bytes + size > self.max_bytes {
+            inner.pop_oldest();
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.order.insert(tick, hash.clone());
+        inner.entries.insert(hash.clone(), (data, tick));
+        inner.bytes += size;
+    }
+
+    pub fn remove(&self, hash: &FileHash) {
+        self.inner.lock().unwrap().remove(hash);
+    }
+
+    pub fn stats(&self) -> MemoryCacheStats {
+        let inner = self.inner.lock().unwrap();
+        MemoryCacheStats {
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+        }
+    }
+}
+
+#[test]
+fn test_memory_cache() {
+    let hash = |c: &str| FileHash::parse(&c.repeat(32)).unwrap();
+    let cache = MemoryCache::new(10, 4);
+
+    assert_eq!(cache.get(&hash("a")), None);
+    cache.insert(&hash("a"), Bytes::from_static(b"aaaa"));
+    cache.insert(&hash("b"), Bytes::from_static(b"bbbb"));
+    // 超过单个文件上限
+    cache.insert(&hash("c"), Bytes::from_static(b"ccccc"));
+    assert_eq!(cache.get(&hash("c")), None);
+
+    // a 刚被用过, 放 d 的时候淘汰 b
+    assert_eq!(cache.get(&hash("a")).unwrap(), "aaaa");
+    cache.insert(&hash("d"), Bytes::from_static(b"dddd"));
+    assert_eq!(cache.get(&hash("b")), None);
+    assert!(cache.get(&hash("a")).is_some());
+    assert!(cache.get(&hash("d")).is_some());
+
+    cache.remove(&hash("a"));
+    assert_eq!(
+        cache.stats(),
+        MemoryCacheStats {
+            entries: 1,
+            bytes: 4,
+        }
+    );
+
+    // 第二次没命中才放行, 读完之前不会重复放行
+    assert!(!cache.admit(&hash("e"), 4));
+    assert!(cache.admit(&hash("e"), 4));
+    assert!(!cache.admit(&hash("e"), 4));
+    cache.end_fill(&hash("e"));
+    assert!(!cache.admit(&hash("f"), 5));
+    assert!(!cache.admit(&hash("f"), 5));
+}
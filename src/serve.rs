@@ -1,18 +1,133 @@
-use crate::{config::Config, utils::hash_to_filename};
+use crate::{
+    cluster::{Cluster, FileIndex},
+    config::Config,
+    fetch::serve_from_center,
+    inflight::{Flight, InFlight},
+    memcache::MemoryCache,
+    stats::{count_bytes, count_measure_bytes, Counters},
+    storage::Storage,
+    utils::{check_sign, FileHash},
+    PROTOCOL_VERSION,
+};
 
 use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, OnceLock};
 
 use axum::{
-    extract::{Path, Query, State},
-    http::header::HeaderMap,
-    http::StatusCode,
+    body::{Body, Bytes},
+    extract::{Path, Query, Request, State},
+    http::header::{self, HeaderMap, HeaderValue},
+    http::{Method, StatusCode},
+    middleware::{self, Next},
     response::{IntoResponse, Response},
+    routing::get,
+    Router,
 };
+use futures_util::stream;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::Client as reqClient;
+use tokio::io::AsyncReadExt;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+
+/// 各个请求处理函数共享的状态
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Config,
+    pub counters: Arc<Counters>,
+    /// 请求 center 用的 client
+    pub client: reqClient,
+    /// 正在从 center 拉取的文件, 和同步共用
+    pub inflight: InFlight,
+    /// 当前文件列表的 hash -> path
+    pub files: FileIndex,
+    /// 缓存文件的存储
+    pub storage: Arc<dyn Storage>,
+    /// 热点文件的内存缓存, 没有配置时为 None
+    pub memory: Option<Arc<MemoryCache>>,
+}
+
+impl AppState {
+    pub fn new(
+        config: Config,
+        counters: Arc<Counters>,
+        inflight: InFlight,
+        storage: Arc<dyn Storage>,
+        memory: Option<Arc<MemoryCache>>,
+    ) -> Self {
+        let client = reqClient::builder()
+            .user_agent(format!("openbmclapi-cluster/{}", PROTOCOL_VERSION))
+            .build()
+            .unwrap();
+        Self {
+            config,
+            counters,
+            client,
+            inflight,
+            files: FileIndex::new(),
+            storage,
+            memory,
+        }
+    }
+
+    /// 和 cluster 共用计数器, 正在拉取的文件, 存储和内存缓存
+    pub fn from_cluster(cluster: &Cluster) -> Self {
+        let mut state = Self::new(
+            cluster.config.clone(),
+            cluster.counters.clone(),
+            cluster.inflight.clone(),
+            cluster.storage.clone(),
+            cluster.memory.clone(),
+        );
+        state.files = cluster.files.clone();
+        state
+    }
+}
+
+/// ```typescript
+/// app.get('/download/:hash(\\w+)', ...)
+/// app.use('/measure', MeasureRoute)
+/// ```
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/download/:hash", get(res_donwload))
+        .route("/measure/:size", get(measure))
+        .layer(middleware::from_fn_with_state(state.clone(), record_status))
+        .with_state(state)
+}
+
+/// 按状态码分类统计所有响应
+async fn record_status(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let res = next.run(req).await;
+    state.counters.record_status(res.status());
+    res
+}
+
+/// 测速的最大大小, 单位 MiB
+pub const MEASURE_MAX_SIZE: u32 = 200;
+
+/// 测速用的 1 MiB 数据, 内容是重复的 0066ccff
+fn measure_buffer() -> Bytes {
+    static BUFFER: OnceLock<Bytes> = OnceLock::new();
+    BUFFER
+        .get_or_init(|| {
+            [0x00_u8, 0x66, 0xcc, 0xff]
+                .iter()
+                .copied()
+                .cycle()
+                .take(1024 * 1024)
+                .collect::<Vec<u8>>()
+                .into()
+        })
+        .clone()
+}
 
 pub enum MeasureRes {
     Forbidden,
     BadResquest,
-    Data(Vec<u8>),
+    /// 大小, 单位 MiB, 发出去的字节数计入 counters 的测速流量
+    Data(u32, Arc<Counters>),
 }
 
 impl IntoResponse for MeasureRes {
@@ -20,7 +135,19 @@ impl IntoResponse for MeasureRes {
         match self {
             Self::Forbidden => (StatusCode::FORBIDDEN).into_response(),
             Self::BadResquest => (StatusCode::BAD_REQUEST).into_response(),
-            Self::Data(data) => (StatusCode::OK, data).into_response(),
+            Self::Data(size, counters) => {
+                let buffer = measure_buffer();
+                let len = buffer.len() as u64 * size as u64;
+                let body =
+                    stream::iter((0..size).map(move |_| Ok::<_, Infallible>(buffer.clone())));
+                let body = count_measure_bytes(counters, body);
+                (
+                    StatusCode::OK,
+                    [(header::CONTENT_LENGTH, len)],
+                    Body::from_stream(body),
+                )
+                    .into_response()
+            }
         }
     }
 }
@@ -49,44 +176,893 @@ impl IntoResponse for MeasureRes {
 ///
 /// export default MeasureRoute
 /// ```
-pub async fn measure(header: HeaderMap, Path(size): Path<u32>) -> MeasureRes {
+pub async fn measure(
+    State(state): State<AppState>,
+    header: HeaderMap,
+    Path(size): Path<u32>,
+) -> MeasureRes {
     match header.get("x-openbmclapi-secret") {
         Some(secret) => {
-            if secret != "secret" {
+            if secret.as_bytes() != state.config.cluster_secret.as_bytes() {
                 return MeasureRes::Forbidden;
             }
-            if size > 200 {
+            if size > MEASURE_MAX_SIZE {
                 return MeasureRes::BadResquest;
             }
-            // size -> size * mb
-            let mut data: Vec<u8> = Vec::with_capacity((size * 1024 * 1024) as usize);
-            data.fill(114_u8);
-            return MeasureRes::Data(data);
+            // 按 MiB 流式返回, 不会一次性占用 size MiB 内存
+            MeasureRes::Data(size, state.counters.clone())
         }
         None => MeasureRes::Forbidden,
     }
 }
 
+/// Range 头的解析结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// 没有 Range 或者无法识别, 返回整个文件
+    Full,
+    /// 闭区间 [start, end]
+    Partial(u64, u64),
+    /// 416
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// 解析单个 `bytes=` 范围, 支持 `a-b`, `a-` 和 `-n`
+    /// 多个范围和语法错误都按照没有 Range 处理
+    pub fn parse(range: &str, size: u64) -> Self {
+        let Some(spec) = range.trim().strip_prefix("bytes=") else {
+            return Self::Full;
+        };
+        if spec.contains(',') {
+            return Self::Full;
+        }
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Self::Full;
+        };
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            // 后 n 个字节
+            let Ok(suffix) = end.parse::<u64>() else {
+                return Self::Full;
+            };
+            if suffix == 0 || size == 0 {
+                return Self::Unsatisfiable;
+            }
+            return Self::Partial(size.saturating_sub(suffix), size - 1);
+        }
+        let Ok(start) = start.parse::<u64>() else {
+            return Self::Full;
+        };
+        let end = if end.is_empty() {
+            u64::MAX
+        } else {
+            match end.parse::<u64>() {
+                Ok(end) if end >= start => end,
+                _ => return Self::Full,
+            }
+        };
+        if start >= size {
+            return Self::Unsatisfiable;
+        }
+        Self::Partial(start, end.min(size - 1))
+    }
+}
+
+/// 基于 hash 的强 ETag
+pub fn hash_etag(hash: &str) -> String {
+    format!("\"{}\"", hash)
+}
+
+/// 文件按 hash 寻址, 内容永远不会变
+pub const DOWNLOAD_CACHE_CONTROL: &str = "public, max-age=2592000, immutable";
+
+/// If-None-Match 是否命中, 按照弱比较, 支持 `*` 和多个 ETag
+pub fn if_none_match(header: &HeaderMap, etag: &str) -> bool {
+    header
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// RFC 5987 中 attr-char 以外的字符都需要编码
+const ATTR_CHAR_ESCAPE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'!')
+    .remove(b'#')
+    .remove(b'$')
+    .remove(b'&')
+    .remove(b'+')
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'^')
+    .remove(b'_')
+    .remove(b'`')
+    .remove(b'|')
+    .remove(b'~');
+
+/// 按照 RFC 6266 生成 `attachment` 的 Content-Disposition
+/// `filename` 是替换掉非 ASCII 字符的回退, `filename*` 是 UTF-8 的原名
+pub fn content_disposition(name: &str) -> HeaderValue {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    let encoded = utf8_percent_encode(name, ATTR_CHAR_ESCAPE);
+    let value = format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    );
+    // 只包含可见 ASCII 字符, 不会失败
+    HeaderValue::from_str(&value).unwrap()
+}
+
+/// 根据请求的文件名或者文件列表中的 path 猜测 Content-Type
+pub fn guess_content_type(req_name: Option<&str>, path: Option<&str>) -> HeaderValue {
+    let mime = req_name
+        .and_then(|name| mime_guess::from_path(name).first())
+        .or_else(|| path.and_then(|path| mime_guess::from_path(path).first()))
+        .unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM);
+    HeaderValue::from_str(mime.as_ref()).unwrap()
+}
+
+/// 下载响应共有的头
+pub fn insert_file_headers(
+    header: &mut HeaderMap,
+    state: &AppState,
+    hash: &str,
+    req_name: Option<&String>,
+) {
+    header.insert("x-bmclapi-hash", hash.parse().unwrap());
+    header.insert(header::ETAG, hash_etag(hash).parse().unwrap());
+    header.insert(
+        header::CACHE_CONTROL,
+        DOWNLOAD_CACHE_CONTROL.parse().unwrap(),
+    );
+    if let Some(req_name) = req_name {
+        header.insert(header::CONTENT_DISPOSITION, content_disposition(req_name));
+    }
+    let path = state.files.path_of(hash);
+    header.insert(
+        header::CONTENT_TYPE,
+        guess_content_type(req_name.map(|name| name.as_str()), path.as_deref()),
+    );
+}
+
+/// 等待别人从 center 拉取同一个文件时, 最多等几次
+/// 每次失败之后重新 join, 可能轮到自己去拉取
+const FOLLOW_ATTEMPTS: u32 = 2;
+
 /// 返回文件的请求函数
+/// ```typescript
 /// app.get('/download/:hash(\\w+)', async (req: Request, res: Response, next: NextFunction) => {
+///   const hash = req.params.hash.toLowerCase()
+///   const signValid = checkSign(hash, this.clusterSecret, req.query as NodeJS.Dict<string>)
+///   if (!signValid) return res.status(403).send('invalid sign')
+///   const hashPath = join(this.cacheDir, hashToFilename(hash))
+///   if (!await fse.pathExists(hashPath)) return res.sendStatus(404)
+///   res.set('x-bmclapi-hash', hash)
+///   if (req.query.name) res.attachment(req.query.name as string)
+///   const {bytes} = await this.storage.express(hashPath, req, res, next)
+///   this.counters.bytes += bytes
+///   this.counters.hits++
+/// })
+/// ```
 pub async fn res_donwload(
-    State(config): State<Config>,
+    State(state): State<AppState>,
+    method: Method,
     header: HeaderMap,
     Query(param): Query<HashMap<String, String>>,
     Path(hash): Path<String>,
-) -> impl IntoResponse {
-    let hash = hash.to_lowercase();
-    let file_path = config.cache_dir.join(hash_to_filename(&hash));
+) -> Response {
+    // 格式不对的 hash 直接 400, 不会碰到文件系统
+    let file_hash = match FileHash::parse(&hash.to_lowercase()) {
+        Ok(file_hash) => file_hash,
+        Err(_) => return (StatusCode::BAD_REQUEST, "invalid hash").into_response(),
+    };
+    let hash = file_hash.as_str();
+    if !check_sign(hash, &state.config.cluster_secret, &param) {
+        state.counters.sign_failure();
+        return (StatusCode::FORBIDDEN, "invalid sign").into_response();
+    }
     let req_name = param.get("name");
-    let mut res = Response::builder().status(StatusCode::OK);
+    if if_none_match(&header, &hash_etag(hash)) {
+        let mut res = Response::builder().status(StatusCode::NOT_MODIFIED);
+        insert_file_headers(res.headers_mut().unwrap(), &state, hash, req_name);
+        return res.body(Body::empty()).unwrap();
+    }
+    let head = method == Method::HEAD;
+    // WebDAV 之类的存储确认文件存在之后直接 302 过去, 不经过内存缓存
+    let redirect = state.storage.redirect_url(&file_hash);
+    // 内存缓存命中的话不用读盘
+    let memory = state
+        .memory
+        .as_ref()
+        .filter(|_| !head && redirect.is_none());
+    let in_memory = memory.and_then(|memory| memory.get(&file_hash));
+    if memory.is_some() {
+        state.counters.memory_lookup(in_memory.is_some());
+    }
+    let mut cached = match &in_memory {
+        Some(data) => Ok(Some(data.len() as u64)),
+        None => state.storage.size(&file_hash).await,
+    };
+    // HEAD 不会触发从 center 拉取
+    if !head && matches!(cached, Ok(None)) {
+        // 同一个文件同时只从 center 拉取一次
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match state.inflight.join(hash) {
+                Flight::Leader(guard) => {
+                    // 可能在 join 之前刚好有人拉取完成
+                    cached = state.storage.size(&file_hash).await;
+                    if !matches!(cached, Ok(Some(_))) {
+                        return serve_from_center(&state, &file_hash, req_name, guard).await;
+                    }
+                    guard.finish(true);
+                    break;
+                }
+                Flight::Follower(follower) => {
+                    if follower.wait().await {
+                        cached = state.storage.size(&file_hash).await;
+                        break;
+                    }
+                    // 拉取失败不代表文件不存在
+                    if attempts >= FOLLOW_ATTEMPTS {
+                        return StatusCode::BAD_GATEWAY.into_response();
+                    }
+                }
+            }
+        }
+    }
+    let size = match cached {
+        Ok(Some(size)) => size,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            warn!("read {} from storage failed: {:?}", hash, err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    // If-Range 和 ETag 不一致时, 忽略 Range 返回整个文件
+    let range = match header.get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        Some(range) => {
+            let if_range_ok = header
+                .get(header::IF_RANGE)
+                .is_none_or(|v| v.as_bytes() == hash_etag(hash).as_bytes());
+            if if_range_ok {
+                ByteRange::parse(range, size)
+            } else {
+                ByteRange::Full
+            }
+        }
+        None => ByteRange::Full,
+    };
+    let (status, start, len) = match range {
+        ByteRange::Full => (StatusCode::OK, 0, size),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end - start + 1),
+        ByteRange::Unsatisfiable => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [
+                    (header::ACCEPT_RANGES, "bytes".to_string()),
+                    (header::CONTENT_RANGE, format!("bytes */{}", size)),
+                ],
+            )
+                .into_response();
+        }
+    };
+    // 流量按请求的范围统计, 客户端从存储实际下载了多少无从得知
+    if let Some(url) = redirect {
+        if !head {
+            state.counters.hit();
+            state.counters.add_bytes(len);
+        }
+        return match HeaderValue::from_str(&url) {
+            Ok(location) => (StatusCode::FOUND, [(header::LOCATION, location)]).into_response(),
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+    }
+    let mut res = Response::builder().status(status);
     {
-        let mut header = res.headers_mut().unwrap();
-        header.insert("x-bmclapi-hash", hash.parse().unwrap());
-        if let Some(req_name) = req_name {
-            header.insert("Content-Disposition", req_name.parse().unwrap());
-            // Content-Type
-            header.insert("Content-Type", "application/octet-stream".parse().unwrap());
+        let header = res.headers_mut().unwrap();
+        insert_file_headers(header, &state, hash, req_name);
+        header.insert(header::ACCEPT_RANGES, "bytes".parse().unwrap());
+        header.insert(header::CONTENT_LENGTH, len.into());
+        if status == StatusCode::PARTIAL_CONTENT {
+            let content_range = format!("bytes {}-{}/{}", start, start + len - 1, size);
+            header.insert(header::CONTENT_RANGE, content_range.parse().unwrap());
+        }
+    }
+    if head {
+        return res.body(Body::empty()).unwrap();
+    }
+    if let Some(data) = in_memory {
+        state.counters.hit();
+        let data = data.slice(start as usize..(start + len) as usize);
+        let body = count_bytes(state.counters.clone(), memory_chunks(data));
+        return res.body(Body::from_stream(body)).unwrap();
+    }
+    let file = match state.storage.open(&file_hash, start, len).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return StatusCode::NOT_FOUND.into_response()
         }
+        Err(err) => {
+            warn!("open {} from storage failed: {:?}", hash, err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    // 这次照常从存储发送, 第二次请求的时候才在后台读进内存
+    if let Some(memory) = memory {
+        if memory.admit(&file_hash, size) {
+            tokio::spawn(fill_memory(
+                state.storage.clone(),
+                memory.clone(),
+                file_hash.clone(),
+                size,
+            ));
+        }
+    }
+    state.counters.hit();
+    let body = count_bytes(state.counters.clone(), ReaderStream::new(file));
+    res.body(Body::from_stream(body)).unwrap()
+}
+
+/// 内存中的文件按块发送, 这样客户端断开时统计的字节数也是准确的
+const MEMORY_CHUNK_SIZE: usize = 64 * 1024;
+
+fn memory_chunks(data: Bytes) -> impl futures_util::Stream<Item = Result<Bytes, Infallible>> {
+    let chunks = (0..data.len())
+        .step_by(MEMORY_CHUNK_SIZE)
+        .map(move |start| Ok(data.slice(start..data.len().min(start + MEMORY_CHUNK_SIZE))));
+    stream::iter(chunks)
+}
+
+/// 在后台从存储读出整个文件放进内存缓存, 不会拖慢触发它的请求
+async fn fill_memory(
+    storage: Arc<dyn Storage>,
+    memory: Arc<MemoryCache>,
+    hash: FileHash,
+    size: u64,
+) {
+    let mut data = Vec::with_capacity(size as usize);
+    let read = match storage.open(&hash, 0, size).await {
+        Ok(mut file) => file.read_to_end(&mut data).await,
+        Err(err) => Err(err),
+    };
+    match read {
+        Ok(_) if data.len() as u64 == size => memory.insert(&hash, Bytes::from(data)),
+        Ok(read) => warn!(
+            "read {} into memory cache got {} of {} bytes",
+            hash, read, size
+        ),
+        Err(err) => warn!("read {} into memory cache failed: {:?}", hash, err),
     }
-    todo!();
+    memory.end_fill(&hash);
+}
+
+#[cfg(test)]
+fn sign(hash: &str, secret: &str) -> HashMap<String, String> {
+    use base64::Engine;
+    use sha1::{Digest, Sha1};
+
+    // 36 进制的毫秒时间戳, 大约是 5000 年以后
+    let e = "zzzzzzzzz".to_string();
+    let mut hasher = Sha1::new();
+    hasher.update(secret);
+    hasher.update(hash);
+    hasher.update(&e);
+    let s = base64::engine::general_purpose::URL_SAFE.encode(hasher.finalize());
+    HashMap::from([("s".to_string(), s), ("e".to_string(), e)])
+}
+
+/// 测试用的 state, 存储和内存缓存按照配置创建
+#[cfg(test)]
+fn test_state(config: Config) -> AppState {
+    let storage = crate::storage::from_config(&config);
+    let memory = MemoryCache::from_config(&config).map(Arc::new);
+    AppState::new(
+        config,
+        Arc::new(Counters::new()),
+        InFlight::new(),
+        storage,
+        memory,
+    )
+}
+
+#[cfg(test)]
+fn cached_path(cache_dir: &std::path::Path, hash: &str) -> std::path::PathBuf {
+    cache_dir.join(crate::utils::hash_to_filename(
+        &FileHash::parse(hash).unwrap(),
+    ))
+}
+
+#[tokio::test]
+async fn test_res_download() {
+    let cache_dir = std::env::temp_dir().join("openbmclapi_rs_test_res_download");
+    let _ = tokio::fs::remove_dir_all(&cache_dir).await;
+    let config = Config::new(
+        None,
+        "127.0.0.1".to_string(),
+        None,
+        "id".to_string(),
+        "secret".to_string(),
+        None,
+        Some(cache_dir.clone()),
+        None,
+    );
+    let state = test_state(config);
+    let hash = "5d41402abc4b2a76b9719d911017c592";
+    crate::utils::safe_write_file(&cached_path(&cache_dir, hash), b"hello")
+        .await
+        .unwrap();
+
+    let res = res_donwload(
+        State(state.clone()),
+        Method::GET,
+        HeaderMap::new(),
+        Query(HashMap::new()),
+        Path(hash.to_string()),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let bad = "..%2F..%2Fetc%2Fpasswd";
+    let res = res_donwload(
+        State(state.clone()),
+        Method::GET,
+        HeaderMap::new(),
+        Query(sign(bad, "secret")),
+        Path(bad.to_string()),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = res_donwload(
+        State(state.clone()),
+        Method::GET,
+        HeaderMap::new(),
+        Query(sign(hash, "secret")),
+        Path(hash.to_string()),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-length"], "5");
+    assert_eq!(res.headers()["x-bmclapi-hash"], hash);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"hello");
+    assert_eq!(state.counters.snapshot(), (1, 5));
+
+    let mut range = HeaderMap::new();
+    range.insert(header::RANGE, "bytes=1-".parse().unwrap());
+    let res = res_donwload(
+        State(state.clone()),
+        Method::GET,
+        range,
+        Query(sign(hash, "secret")),
+        Path(hash.to_string()),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.headers()["content-range"], "bytes 1-4/5");
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"ello");
+    assert_eq!(state.counters.snapshot(), (2, 9));
+
+    let mut if_none_match = HeaderMap::new();
+    if_none_match.insert(header::IF_NONE_MATCH, hash_etag(hash).parse().unwrap());
+    let res = res_donwload(
+        State(state.clone()),
+        Method::GET,
+        if_none_match,
+        Query(sign(hash, "secret")),
+        Path(hash.to_string()),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(res.headers()["etag"], hash_etag(hash));
+
+    let res = res_donwload(
+        State(state.clone()),
+        Method::HEAD,
+        HeaderMap::new(),
+        Query(sign(hash, "secret")),
+        Path(hash.to_string()),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-length"], "5");
+    assert_eq!(res.headers()["cache-control"], DOWNLOAD_CACHE_CONTROL);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert!(body.is_empty());
+    // 304 和 HEAD 不计入流量
+    assert_eq!(state.counters.snapshot(), (2, 9));
+
+    tokio::fs::remove_dir_all(&cache_dir).await.unwrap();
+}
+
+/// 302 到固定地址的本地存储
+#[cfg(test)]
+struct RedirectStorage(crate::storage::LocalStorage);
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl Storage for RedirectStorage {
+    async fn size(&self, hash: &FileHash) -> std::io::Result<Option<u64>> {
+        self.0.size(hash).await
+    }
+
+    async fn open(
+        &self,
+        hash: &FileHash,
+        start: u64,
+        len: u64,
+    ) -> std::io::Result<crate::storage::FileReader> {
+        self.0.open(hash, start, len).await
+    }
+
+    async fn write_stream(
+        &self,
+        hash: &FileHash,
+        reader: crate::storage::FileReader,
+        size: Option<u64>,
+    ) -> std::io::Result<()> {
+        self.0.write_stream(hash, reader, size).await
+    }
+
+    async fn delete_key(&self, key: &str) -> std::io::Result<()> {
+        self.0.delete_key(key).await
+    }
+
+    async fn list(&self) -> std::io::Result<Vec<crate::storage::StoredFile>> {
+        self.0.list().await
+    }
+
+    async fn free_space(&self) -> std::io::Result<Option<u64>> {
+        self.0.free_space().await
+    }
+
+    fn redirect_url(&self, hash: &FileHash) -> Option<String> {
+        Some(format!("http://nas/{}", hash.as_str()))
+    }
+}
+
+#[tokio::test]
+async fn test_redirect_download() {
+    let cache_dir = std::env::temp_dir().join("openbmclapi_rs_test_redirect_download");
+    let _ = tokio::fs::remove_dir_all(&cache_dir).await;
+    let mut state = test_state(Config::new(
+        None,
+        "127.0.0.1".to_string(),
+        None,
+        "id".to_string(),
+        "secret".to_string(),
+        None,
+        Some(cache_dir.clone()),
+        None,
+    ));
+    state.storage = Arc::new(RedirectStorage(crate::storage::LocalStorage::new(
+        cache_dir.clone(),
+    )));
+    let hash = "5d41402abc4b2a76b9719d911017c592";
+    let missing = "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d";
+    crate::utils::safe_write_file(&cached_path(&cache_dir, hash), b"hello")
+        .await
+        .unwrap();
+
+    let download = |method: Method, hash: &'static str, range: Option<&str>| {
+        let mut header = HeaderMap::new();
+        if let Some(range) = range {
+            header.insert(header::RANGE, range.parse().unwrap());
+        }
+        res_donwload(
+            State(state.clone()),
+            method,
+            header,
+            Query(sign(hash, "secret")),
+            Path(hash.to_string()),
+        )
+    };
+    // 还没同步的文件不会被 302 到存储上
+    let res = download(Method::HEAD, missing, None).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = download(Method::GET, hash, Some("bytes=1-3")).await;
+    assert_eq!(res.status(), StatusCode::FOUND);
+    assert_eq!(res.headers()["location"], format!("http://nas/{}", hash));
+    // 按请求的范围统计流量
+    assert_eq!(state.counters.snapshot(), (1, 3));
+
+    tokio::fs::remove_dir_all(&cache_dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_memory_cache_download() {
+    let cache_dir = std::env::temp_dir().join("openbmclapi_rs_test_memory_cache_download");
+    let _ = tokio::fs::remove_dir_all(&cache_dir).await;
+    let mut config = Config::new(
+        None,
+        "127.0.0.1".to_string(),
+        None,
+        "id".to_string(),
+        "secret".to_string(),
+        None,
+        Some(cache_dir.clone()),
+        None,
+    );
+    config.memory_cache_mb = 1;
+    let state = test_state(config);
+    let hash = "5d41402abc4b2a76b9719d911017c592";
+    let path = cached_path(&cache_dir, hash);
+    crate::utils::safe_write_file(&path, b"hello")
+        .await
+        .unwrap();
+
+    let download = |range: Option<&str>| {
+        let mut header = HeaderMap::new();
+        if let Some(range) = range {
+            header.insert(header::RANGE, range.parse().unwrap());
+        }
+        res_donwload(
+            State(state.clone()),
+            Method::GET,
+            header,
+            Query(sign(hash, "secret")),
+            Path(hash.to_string()),
+        )
+    };
+    let memory = state.memory.clone().unwrap();
+    // 前两次都从存储发送, 第二次之后在后台读进内存
+    for _ in 0..2 {
+        let res = download(None).await;
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"hello");
+    }
+    for _ in 0..50 {
+        if memory.stats().entries == 1 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(memory.stats().entries, 1);
+
+    // 之后不会读盘
+    tokio::fs::remove_file(&path).await.unwrap();
+    let res = download(Some("bytes=1-3")).await;
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"ell");
+
+    assert_eq!(state.counters.memory_lookups(), (1, 2));
+    assert_eq!(state.counters.snapshot(), (3, 13));
+    tokio::fs::remove_dir_all(&cache_dir).await.unwrap();
+}
+
+#[test]
+fn test_byte_range_parse() {
+    assert_eq!(
+        ByteRange::parse("bytes=0-499", 1000),
+        ByteRange::Partial(0, 499)
+    );
+    assert_eq!(
+        ByteRange::parse("bytes=500-", 1000),
+        ByteRange::Partial(500, 999)
+    );
+    assert_eq!(
+        ByteRange::parse("bytes=-200", 1000),
+        ByteRange::Partial(800, 999)
+    );
+    assert_eq!(
+        ByteRange::parse("bytes=-2000", 1000),
+        ByteRange::Partial(0, 999)
+    );
+    assert_eq!(
+        ByteRange::parse("bytes=900-2000", 1000),
+        ByteRange::Partial(900, 999)
+    );
+    assert_eq!(
+        ByteRange::parse("bytes=1000-", 1000),
+        ByteRange::Unsatisfiable
+    );
+    assert_eq!(ByteRange::parse("bytes=-0", 1000), ByteRange::Unsatisfiable);
+    assert_eq!(ByteRange::parse("bytes=5-1", 1000), ByteRange::Full);
+    assert_eq!(ByteRange::parse("bytes=0-1,5-6", 1000), ByteRange::Full);
+    assert_eq!(ByteRange::parse("items=0-1", 1000), ByteRange::Full);
+}
+
+#[tokio::test]
+async fn test_fetch_on_miss() {
+    use axum::routing::get;
+
+    // 假的 center
+    let center = axum::Router::new().route(
+        "/openbmclapi/download/:hash",
+        get(|Path(hash): Path<String>| async move {
+            match hash.as_str() {
+                "5d41402abc4b2a76b9719d911017c592" => (StatusCode::OK, "hello").into_response(),
+                "0123456789abcdef0123456789abcdef" => (StatusCode::OK, "corrupted").into_response(),
+                "7d793037a0760186574b0282f2f435e7" => (StatusCode::OK, "world").into_response(),
+                "ffffffffffffffffffffffffffffffff" => {
+                    StatusCode::SERVICE_UNAVAILABLE.into_response()
+                }
+                _ => StatusCode::NOT_FOUND.into_response(),
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let center_url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, center).await.unwrap() });
+
+    let cache_dir = std::env::temp_dir().join("openbmclapi_rs_test_fetch_on_miss");
+    let _ = tokio::fs::remove_dir_all(&cache_dir).await;
+    let config = Config::new(
+        Some(center_url),
+        "127.0.0.1".to_string(),
+        None,
+        "id".to_string(),
+        "secret".to_string(),
+        None,
+        Some(cache_dir.clone()),
+        None,
+    );
+    let state = test_state(config);
+
+    let fetch = |hash: &'static str| {
+        res_donwload(
+            State(state.clone()),
+            Method::GET,
+            HeaderMap::new(),
+            Query(sign(hash, "secret")),
+            Path(hash.to_string()),
+        )
+    };
+
+    let hash = "5d41402abc4b2a76b9719d911017c592";
+    let res = fetch(hash).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"hello");
+    let cached = cached_path(&cache_dir, hash);
+    for _ in 0..50 {
+        if cached.exists() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    assert_eq!(tokio::fs::read(&cached).await.unwrap(), b"hello");
+
+    // 校验失败的不会进入缓存, 客户端收到的 body 以错误结束
+    let bad = "0123456789abcdef0123456789abcdef";
+    let res = fetch(bad).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .is_err());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(!cached_path(&cache_dir, bad).exists());
+
+    let missing = "fedcba9876543210fedcba9876543210";
+    assert_eq!(fetch(missing).await.status(), StatusCode::NOT_FOUND);
+    // center 出错不是文件不存在
+    let broken = "ffffffffffffffffffffffffffffffff";
+    assert_eq!(fetch(broken).await.status(), StatusCode::BAD_GATEWAY);
+
+    // 等待的那次拉取失败之后自己去拉取, 不会返回 404
+    let hash = "7d793037a0760186574b0282f2f435e7";
+    let Flight::Leader(guard) = state.inflight.join(hash) else {
+        panic!("should lead");
+    };
+    let follower = tokio::spawn(fetch(hash));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    drop(guard);
+    let res = follower.await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"world");
+
+    // 同时请求同一个文件, 只有一个会去 center 拉取
+    let hash = "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d";
+    let Flight::Leader(guard) = state.inflight.join(hash) else {
+        panic!("should lead");
+    };
+    let follower = tokio::spawn(fetch(hash));
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(!follower.is_finished());
+    crate::utils::safe_write_file(&cached_path(&cache_dir, hash), b"hello")
+        .await
+        .unwrap();
+    guard.finish(true);
+    let res = follower.await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(&body[..], b"hello");
+
+    tokio::fs::remove_dir_all(&cache_dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_measure() {
+    let config = Config::new(
+        None,
+        "127.0.0.1".to_string(),
+        None,
+        "id".to_string(),
+        "secret".to_string(),
+        None,
+        None,
+        None,
+    );
+    let state = test_state(config);
+    let mut header = HeaderMap::new();
+    header.insert("x-openbmclapi-secret", "wrong".parse().unwrap());
+    let res = measure(State(state.clone()), header, Path(1))
+        .await
+        .into_response();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let mut header = HeaderMap::new();
+    header.insert("x-openbmclapi-secret", "secret".parse().unwrap());
+    let res = measure(State(state.clone()), header.clone(), Path(201))
+        .await
+        .into_response();
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let res = measure(State(state.clone()), header, Path(2))
+        .await
+        .into_response();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()["content-length"], "2097152");
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        .await
+        .unwrap();
+    assert_eq!(body.len(), 2 * 1024 * 1024);
+    assert_eq!(
+        &body[..8],
+        &[0x00, 0x66, 0xcc, 0xff, 0x00, 0x66, 0xcc, 0xff]
+    );
+    // 测速流量单独统计, 不算进上报的 bytes
+    assert_eq!(state.counters.measure_bytes(), 2 * 1024 * 1024);
+    assert_eq!(state.counters.snapshot(), (0, 0));
+}
+
+#[test]
+fn test_content_disposition() {
+    assert_eq!(
+        content_disposition("mod.jar"),
+        "attachment; filename=\"mod.jar\"; filename*=UTF-8''mod.jar"
+    );
+    assert_eq!(
+        content_disposition("精致模组 \"1\".jar"),
+        "attachment; filename=\"____ _1_.jar\"; \
+         filename*=UTF-8''%E7%B2%BE%E8%87%B4%E6%A8%A1%E7%BB%84%20%221%22.jar"
+    );
+    assert_eq!(guess_content_type(Some("a.json"), None), "application/json");
+    assert_eq!(
+        guess_content_type(None, Some("/version/1.20/client.jar")),
+        "application/java-archive"
+    );
+    assert_eq!(guess_content_type(None, None), "application/octet-stream");
 }
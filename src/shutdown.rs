@@ -0,0 +1,67 @@
+use crate::cluster::Cluster;
+
+use std::time::Duration;
+
+use axum_server::Handle;
+use tracing::{info, warn};
+
+/// 停止接受新连接后, 等待正在进行的下载的最长时间
+pub const GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+/// 等待 SIGINT 或者 SIGTERM
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("got SIGINT"),
+        _ = terminate => info!("got SIGTERM"),
+    }
+}
+
+/// 收到信号之后优雅退出
+/// 1. 向 center 发送 disable (带超时)
+/// 2. 停止接受新的连接
+/// 3. 在 [`GRACE_PERIOD`] 内等待正在进行的下载结束
+/// 4. 断开 socket
+pub async fn graceful_shutdown(cluster: Cluster, handle: Handle) {
+    wait_for_signal().await;
+    info!("shutting down");
+    cluster
+        .shutting_down
+        .store(true, std::sync::atomic::Ordering::SeqCst);
+
+    if cluster.is_enabled() {
+        if let Err(err) = cluster.disable().await {
+            warn!("disable before shutdown failed: {:?}", err);
+        }
+    }
+
+    handle.graceful_shutdown(Some(GRACE_PERIOD));
+    let deadline = tokio::time::Instant::now() + GRACE_PERIOD;
+    while handle.connection_count() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let remain = handle.connection_count();
+    if remain > 0 {
+        warn!("{} connections still alive after grace period", remain);
+    } else {
+        info!("all connections finished");
+    }
+
+    cluster.disconnect().await;
+}
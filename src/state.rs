@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// 节点的生命周期状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ClusterState {
+    /// socket 未连接
+    Disconnected,
+    /// 正在连接 center
+    Connecting,
+    /// socket 已连接, 但是没有 enable
+    Connected,
+    /// 正在同步文件
+    Syncing,
+    /// 已经发送 enable, 等待 ack
+    Enabling,
+    /// 正在对外提供服务
+    Enabled,
+    /// 已经发送 disable, 等待 ack
+    Disabling,
+}
+
+impl ClusterState {
+    /// 是否允许从 self 转换到 next
+    /// 任何状态都可以因为断线回到 Disconnected
+    pub fn can_transition_to(self, next: ClusterState) -> bool {
+        use ClusterState::*;
+        if next == Disconnected {
+            return true;
+        }
+        matches!(
+            (self, next),
+            (Disconnected, Connecting)
+                | (Connecting, Connected)
+                | (Connected, Syncing)
+                | (Connected, Enabling)
+                | (Syncing, Connected)
+                | (Syncing, Enabling)
+                | (Enabling, Enabled)
+                | (Enabling, Connected)
+                | (Enabled, Disabling)
+                | (Disabling, Connected)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub from: ClusterState,
+    pub to: ClusterState,
+}
+
+/// 持有当前状态, clone 出来的 handle 共享同一个状态
+#[derive(Clone)]
+pub struct StateHandle {
+    tx: Arc<watch::Sender<ClusterState>>,
+}
+
+impl Default for StateHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateHandle {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(ClusterState::Disconnected);
+        Self { tx: Arc::new(tx) }
+    }
+
+    pub fn get(&self) -> ClusterState {
+        *self.tx.borrow()
+    }
+
+    /// 转换到 next, 成功时返回之前的状态
+    /// 转换到相同的状态什么都不做
+    pub fn transition(&self, next: ClusterState) -> Result<ClusterState, InvalidTransition> {
+        let mut result = Ok(next);
+        self.tx.send_if_modified(|current| {
+            let prev = *current;
+            if prev == next {
+                result = Ok(prev);
+                return false;
+            }
+            if !prev.can_transition_to(next) {
+                result = Err(InvalidTransition {
+                    from: prev,
+                    to: next,
+                });
+                return false;
+            }
+            *current = next;
+            result = Ok(prev);
+            true
+        });
+        match result {
+            Ok(prev) if prev != next => info!("cluster state: {:?} -> {:?}", prev, next),
+            Err(err) => warn!(
+                "invalid cluster state transition: {:?} -> {:?}",
+                err.from, err.to
+            ),
+            _ => (),
+        }
+        result
+    }
+}
+
+#[test]
+fn test_state_transition() {
+    let state = StateHandle::new();
+    assert_eq!(state.get(), ClusterState::Disconnected);
+    assert!(state.transition(ClusterState::Enabled).is_err());
+    assert_eq!(
+        state.transition(ClusterState::Connecting),
+        Ok(ClusterState::Disconnected)
+    );
+    assert_eq!(state.clone().get(), ClusterState::Connecting);
+    state.transition(ClusterState::Connected).unwrap();
+    state.transition(ClusterState::Syncing).unwrap();
+    state.transition(ClusterState::Enabling).unwrap();
+    state.transition(ClusterState::Enabled).unwrap();
+    assert!(state.transition(ClusterState::Syncing).is_err());
+    assert_eq!(state.get(), ClusterState::Enabled);
+    assert_eq!(
+        state.transition(ClusterState::Disconnected),
+        Ok(ClusterState::Enabled)
+    );
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use axum::{body::Bytes, http::StatusCode};
+use futures_util::{Stream, StreamExt};
+
+/// 下载和测速处理函数共用的统计, 全部是原子操作, 不需要加锁
+///
+/// hits 和 bytes 是自上次 keep-alive 以来的增量, 上报成功后减去已上报的部分;
+/// 状态码分类, 签名失败次数, 测速流量和内存缓存的命中次数是启动以来的累计值
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub hits: AtomicU64,
+    /// 实际发给客户端的字节数, 不是 Content-Length
+    pub bytes: AtomicU64,
+    /// 1xx 到 5xx 的响应数
+    pub status: [AtomicU64; 5],
+    pub sign_failures: AtomicU64,
+    /// 测速发出去的字节数, 不是文件流量, 不上报给 center
+    pub measure_bytes: AtomicU64,
+    /// 内存缓存命中和没命中的下载请求数
+    pub memory_hits: AtomicU64,
+    pub memory_misses: AtomicU64,
+}
+
+impl Counters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一次下载请求, 字节数由 [`count_bytes`] 在发送时累加
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_status(&self, status: StatusCode) {
+        let class = (status.as_u16() / 100) as usize;
+        if let Some(counter) = class.checked_sub(1).and_then(|i| self.status.get(i)) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn add_measure_bytes(&self, bytes: u64) {
+        self.measure_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn memory_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.memory_hits
+        } else {
+            &self.memory_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn sign_failure(&self) {
+        self.sign_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 返回 (hits, bytes)
+    pub fn snapshot(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    /// 减去已经上报的部分, 上报期间新增的数据会留到下一次
+    pub fn subtract(&self, hits: u64, bytes: u64) {
+        self.hits.fetch_sub(hits, Ordering::Relaxed);
+        self.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// 1xx 到 5xx 的响应数
+    pub fn status_classes(&self) -> [u64; 5] {
+        self.status
+            .each_ref()
+            .map(|counter| counter.load(Ordering::Relaxed))
+    }
+
+    pub fn sign_failures(&self) -> u64 {
+        self.sign_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn measure_bytes(&self) -> u64 {
+        self.measure_bytes.load(Ordering::Relaxed)
+    }
+
+    /// 返回 (命中, 没命中)
+    pub fn memory_lookups(&self) -> (u64, u64) {
+        (
+            self.memory_hits.load(Ordering::Relaxed),
+            self.memory_misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// 在 body 被读取的时候累加字节数
+/// 客户端中途断开的话, 没发出去的部分不会被计入
+pub fn count_bytes<S, E>(
+    counters: Arc<Counters>,
+    body: S,
+) -> impl Stream<Item = Result<Bytes, E>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+{
+    inspect_bytes(body, move |bytes| counters.add_bytes(bytes))
+}
+
+/// 和 [`count_bytes`] 一样, 但是计入测速的流量
+pub fn count_measure_bytes<S, E>(
+    counters: Arc<Counters>,
+    body: S,
+) -> impl Stream<Item = Result<Bytes, E>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+{
+    inspect_bytes(body, move |bytes| counters.add_measure_bytes(bytes))
+}
+
+fn inspect_bytes<S, E, F>(body: S, add: F) -> impl Stream<Item = Result<Bytes, E>> + Send + 'static
+where
+    S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+    F: Fn(u64) + Send + 'static,
+{
+    body.inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            add(chunk.len() as u64);
+        }
+    })
+}
+
+#[test]
+fn test_counters_subtract() {
+    let counters = Counters::new();
+    counters.hit();
+    counters.add_bytes(100);
+    counters.hit();
+    counters.add_bytes(50);
+    let (hits, bytes) = counters.snapshot();
+    assert_eq!((hits, bytes), (2, 150));
+    // 上报期间又来了一个请求
+    counters.hit();
+    counters.add_bytes(10);
+    counters.subtract(hits, bytes);
+    assert_eq!(counters.snapshot(), (1, 10));
+
+    counters.record_status(StatusCode::OK);
+    counters.record_status(StatusCode::PARTIAL_CONTENT);
+    counters.record_status(StatusCode::FORBIDDEN);
+    assert_eq!(counters.status_classes(), [0, 2, 0, 1, 0]);
+}
+
+#[tokio::test]
+async fn test_count_bytes_aborted() {
+    let counters = Arc::new(Counters::new());
+    let chunks = (0..4).map(|_| Ok::<_, std::convert::Infallible>(Bytes::from_static(b"hello")));
+    let mut body = Box::pin(count_bytes(
+        counters.clone(),
+        futures_util::stream::iter(chunks),
+    ));
+    body.next().await.unwrap().unwrap();
+    // 客户端只读了一块就断开了
+    drop(body);
+    assert_eq!(counters.snapshot(), (0, 5));
+}
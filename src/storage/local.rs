@@ -0,0 +1,234 @@
+use super::{
+    check_key, is_shard_dir, trash_batch_time, trash_key_of, FileReader, Storage, StoredFile,
+    TRASH_DIR,
+};
+use crate::utils::{hash_to_filename, tmp_file_path, FileHash};
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+fn dir_size(path: &Path) -> u64 {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(meta) if meta.is_dir() => dir_size(&entry.path()),
+            Ok(meta) => meta.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+/// 本地文件系统, 按照 hash 的前两位分目录
+/// ```text
+/// cache_dir/5d/5d41402abc4b2a76b9719d911017c592
+/// ```
+#[derive(Debug, Clone)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path_of(&self, hash: &FileHash) -> PathBuf {
+        self.root.join(hash_to_filename(hash))
+    }
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    async fn size(&self, hash: &FileHash) -> io::Result<Option<u64>> {
+        match tokio::fs::metadata(self.path_of(hash)).await {
+            Ok(meta) if meta.is_file() => Ok(Some(meta.len())),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn open(&self, hash: &FileHash, start: u64, len: u64) -> io::Result<FileReader> {
+        let mut file = tokio::fs::File::open(self.path_of(hash)).await?;
+        if start > 0 {
+            file.seek(io::SeekFrom::Start(start)).await?;
+        }
+        Ok(Box::pin(file.take(len)))
+    }
+
+    /// 先写到同目录下的临时文件, 完整写完之后再 rename 过去
+    async fn write_stream(
+        &self,
+        hash: &FileHash,
+        mut reader: FileReader,
+        size: Option<u64>,
+    ) -> io::Result<()> {
+        let path = self.path_of(hash);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp_path = tmp_file_path(&path);
+        let written = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await?;
+            let written = tokio::io::copy(&mut reader, &mut file).await?;
+            if size.is_some_and(|size| size != written) {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("expect {:?} bytes, got {}", size, written),
+                ));
+            }
+            file.flush().await?;
+            file.sync_all().await
+        }
+        .await;
+        if let Err(err) = written {
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+            return Err(err);
+        }
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+
+    async fn delete_key(&self, key: &str) -> io::Result<()> {
+        check_key(key)?;
+        match tokio::fs::remove_file(self.root.join(key)).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<StoredFile>> {
+        let mut files = Vec::new();
+        let mut shards = match tokio::fs::read_dir(&self.root).await {
+            Ok(shards) => shards,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(files),
+            Err(err) => return Err(err),
+        };
+        while let Some(shard) = shards.next_entry().await? {
+            let shard_name = shard.file_name().to_string_lossy().to_string();
+            if !shard.file_type().await?.is_dir() || !is_shard_dir(&shard_name) {
+                continue;
+            }
+            let mut entries = tokio::fs::read_dir(shard.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let meta = entry.metadata().await?;
+                if !meta.is_file() {
+                    continue;
+                }
+                let name = entry.file_name().to_string_lossy().to_string();
+                let hash = FileHash::parse(&name)
+                    .ok()
+                    .filter(|hash| hash.as_str().starts_with(&shard_name));
+                files.push(StoredFile {
+                    key: format!("{}/{}", shard_name, name),
+                    hash,
+                    size: meta.len(),
+                    modified: meta.modified().ok(),
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    async fn trash_key(&self, key: &str) -> io::Result<()> {
+        check_key(key)?;
+        let target = self.root.join(trash_key_of(key, SystemTime::now()));
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        match tokio::fs::rename(self.root.join(key), target).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    async fn purge_trash(&self, before: SystemTime) -> io::Result<u64> {
+        let trash = self.root.join(TRASH_DIR);
+        tokio::task::spawn_blocking(move || {
+            let batches = match std::fs::read_dir(&trash) {
+                Ok(batches) => batches,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+                Err(err) => return Err(err),
+            };
+            let mut purged = 0;
+            for batch in batches.flatten() {
+                let Some(time) = batch.file_name().to_str().and_then(trash_batch_time) else {
+                    continue;
+                };
+                if time >= before {
+                    continue;
+                }
+                let size = dir_size(&batch.path());
+                std::fs::remove_dir_all(batch.path())?;
+                purged += size;
+            }
+            Ok(purged)
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    async fn free_space(&self) -> io::Result<Option<u64>> {
+        let root = self.root.clone();
+        tokio::task::spawn_blocking(move || fs2::available_space(root).map(Some))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[tokio::test]
+async fn test_local_storage() {
+    let root = std::env::temp_dir().join("openbmclapi_rs_test_local_storage");
+    let _ = tokio::fs::remove_dir_all(&root).await;
+    let storage = LocalStorage::new(root.clone());
+    let hash = FileHash::parse("5d41402abc4b2a76b9719d911017c592").unwrap();
+
+    assert_eq!(storage.size(&hash).await.unwrap(), None);
+    storage.write(&hash, b"hello").await.unwrap();
+    assert_eq!(storage.size(&hash).await.unwrap(), Some(5));
+    assert!(storage.exists(&hash).await.unwrap());
+
+    let mut part = String::new();
+    let mut reader = storage.open(&hash, 1, 3).await.unwrap();
+    reader.read_to_string(&mut part).await.unwrap();
+    assert_eq!(part, "ell");
+
+    // 分片目录外的文件不会被列出来
+    tokio::fs::write(root.join("5d/stray.tmp"), b"x")
+        .await
+        .unwrap();
+    tokio::fs::write(root.join("cert.pem"), b"x").await.unwrap();
+    let mut files = storage.list().await.unwrap();
+    files.sort_by(|a, b| a.key.cmp(&b.key));
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].hash.as_ref(), Some(&hash));
+    assert_eq!(files[1].key, "5d/stray.tmp");
+    assert_eq!(files[1].hash, None);
+
+    assert!(storage.delete_key("../cert.pem").await.is_err());
+    storage.delete_key("5d/stray.tmp").await.unwrap();
+    storage.delete(&hash).await.unwrap();
+    storage.delete(&hash).await.unwrap();
+    assert_eq!(storage.list().await.unwrap(), vec![]);
+
+    // reader 出错时放弃写入, 不会留下临时文件
+    let chunks = vec![
+        Ok(axum::body::Bytes::from_static(b"hel")),
+        Err(io::Error::other("corrupted")),
+    ];
+    let reader = tokio_util::io::StreamReader::new(futures_util::stream::iter(chunks));
+    assert!(storage
+        .write_stream(&hash, Box::pin(reader), None)
+        .await
+        .is_err());
+    assert_eq!(storage.list().await.unwrap(), vec![]);
+    assert!(storage.free_space().await.unwrap().is_some());
+
+    tokio::fs::remove_dir_all(&root).await.unwrap();
+}
@@ -0,0 +1,176 @@
+mod local;
+mod s3;
+mod webdav;
+
+pub use local::LocalStorage;
+pub use s3::S3Storage;
+pub use webdav::WebdavStorage;
+#[cfg(test)]
+pub(crate) use {s3::spawn_fake_s3, webdav::spawn_fake_webdav};
+
+use crate::config::{Config, StorageKind};
+use crate::fatal;
+use crate::utils::FileHash;
+
+use std::io;
+use std::path::{Component, Path};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use tokio::io::AsyncRead;
+
+/// 连接远程存储的超时
+/// 不限制整个请求的时长, 大文件的上传和转发给慢速客户端的下载可能要很久
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// [`Storage::open`] 返回的数据流
+pub type FileReader = Pin<Box<dyn AsyncRead + Send>>;
+
+/// 存储中的一个文件
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredFile {
+    /// 相对于存储根目录的路径, 例如 `5d/5d41402abc4b2a76b9719d911017c592`
+    pub key: String,
+    /// 文件名是合法的 hash 并且放在对应的分片目录下时才有
+    pub hash: Option<FileHash>,
+    pub size: u64,
+    /// 最后修改时间, 存储不提供时为 None
+    pub modified: Option<SystemTime>,
+}
+
+/// 缓存文件的存储后端
+/// 下载, 同步和清理都通过它访问文件, 不直接拼接路径
+/// 证书和文件列表快照是节点本地的状态, 仍然放在 cache_dir 下
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// 文件大小, 不存在时返回 None
+    async fn size(&self, hash: &FileHash) -> io::Result<Option<u64>>;
+
+    #[cfg(test)]
+    async fn exists(&self, hash: &FileHash) -> io::Result<bool> {
+        Ok(self.size(hash).await?.is_some())
+    }
+
+    /// 从 start 开始读取 len 字节
+    async fn open(&self, hash: &FileHash, start: u64, len: u64) -> io::Result<FileReader>;
+
+    /// 原子写入, 其他人不会读到写了一半的文件
+    #[cfg(test)]
+    async fn write(&self, hash: &FileHash, data: &[u8]) -> io::Result<()> {
+        let reader = io::Cursor::new(data.to_vec());
+        self.write_stream(hash, Box::pin(reader), Some(data.len() as u64))
+            .await
+    }
+
+    /// 从 reader 流式写入, 同样是原子的, 不需要把整个文件放在内存里
+    /// reader 返回错误时放弃这次写入, 调用者可以借此在校验失败时中止
+    /// size 未知时为 None, 需要事先知道大小的存储会返回错误
+    async fn write_stream(
+        &self,
+        hash: &FileHash,
+        reader: FileReader,
+        size: Option<u64>,
+    ) -> io::Result<()>;
+
+    /// 删除文件, 文件不存在不算错误
+    #[cfg(test)]
+    async fn delete(&self, hash: &FileHash) -> io::Result<()> {
+        self.delete_key(&hash_key(hash)).await
+    }
+
+    /// 按照 [`StoredFile::key`] 删除, 用来清理不是 hash 命名的文件
+    async fn delete_key(&self, key: &str) -> io::Result<()>;
+
+    /// 列出所有分片目录下的文件
+    async fn list(&self) -> io::Result<Vec<StoredFile>>;
+
+    /// 移到回收站, 不支持回收站的存储直接删除
+    async fn trash_key(&self, key: &str) -> io::Result<()> {
+        self.delete_key(key).await
+    }
+
+    /// 清理回收站中 before 之前放进去的文件, 返回释放的字节数
+    async fn purge_trash(&self, _before: SystemTime) -> io::Result<u64> {
+        Ok(0)
+    }
+
+    /// 剩余空间, 无法得知时返回 None
+    async fn free_space(&self) -> io::Result<Option<u64>>;
+
+    /// 下载请求直接 302 到这个地址, 而不是由节点转发
+    fn redirect_url(&self, _hash: &FileHash) -> Option<String> {
+        None
+    }
+}
+
+/// hash 在存储中的 key, 和 [`crate::utils::hash_to_filename`] 的布局一致
+pub fn hash_key(hash: &FileHash) -> String {
+    let hash = hash.as_str();
+    format!("{}/{}", &hash[0..2], hash)
+}
+
+/// 回收站目录, 不是分片目录所以不会被 list 列出来
+/// 每次放进去的文件在 `.trash/{unix 秒数}/` 下面, 清理时按批次整个删除
+const TRASH_DIR: &str = ".trash";
+
+/// key 现在放进回收站的话对应的 key
+fn trash_key_of(key: &str, now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    format!("{}/{}/{}", TRASH_DIR, secs, key)
+}
+
+/// 回收站中一个批次的目录名对应的时间, 不是批次目录时返回 None
+fn trash_batch_time(name: &str) -> Option<SystemTime> {
+    let secs = name.parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// 分片目录是两位小写十六进制
+fn is_shard_dir(name: &str) -> bool {
+    name.len() == 2
+        && name
+            .bytes()
+            .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+}
+
+/// key 只能是分片目录下的相对路径, 不能跳出存储的根目录
+fn check_key(key: &str) -> io::Result<()> {
+    let valid = Path::new(key)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if valid && !key.contains('\\') {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid storage key: {:?}", key),
+        ))
+    }
+}
+
+/// 根据配置选择存储后端
+pub fn from_config(config: &Config) -> Arc<dyn Storage> {
+    match config.storage {
+        StorageKind::File => Arc::new(LocalStorage::new(config.cache_dir.clone())),
+        StorageKind::Webdav => {
+            let Some(webdav) = &config.webdav else {
+                fatal!("storage is webdav, but CLUSTER_STORAGE_OPTIONS is not set");
+            };
+            let storage = WebdavStorage::new(webdav).unwrap_or_else(|err| {
+                fatal!("invalid webdav options: {}", err);
+            });
+            Arc::new(storage)
+        }
+        StorageKind::S3 => {
+            let Some(s3) = &config.s3 else {
+                fatal!("storage is s3, but CLUSTER_STORAGE_OPTIONS is not set");
+            };
+            let storage = S3Storage::new(s3).unwrap_or_else(|err| {
+                fatal!("invalid s3 options: {}", err);
+            });
+            Arc::new(storage)
+        }
+    }
+}
@@ -0,0 +1,670 @@
+use super::{
+    check_key, hash_key, is_shard_dir, trash_batch_time, trash_key_of, FileReader, Storage,
+    StoredFile, CONNECT_TIMEOUT, TRASH_DIR,
+};
+use crate::config::WebdavConfig;
+use crate::utils::FileHash;
+
+use std::collections::HashSet;
+use std::io;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use percent_encoding::percent_decode_str;
+use reqwest::{header, Body, Client as reqClient, Method, RequestBuilder, StatusCode, Url};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+
+/// PROPFIND 时请求的属性
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getcontentlength/>
+    <d:quota-available-bytes/>
+  </d:prop>
+</d:propfind>"#;
+
+/// PROPFIND 返回的一项
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DavEntry {
+    /// 解码后的路径
+    path: String,
+    collection: bool,
+    size: Option<u64>,
+    quota_available: Option<u64>,
+}
+
+impl DavEntry {
+    /// 路径的最后一段
+    fn name(&self) -> &str {
+        self.path
+            .trim_end_matches('/')
+            .rsplit('/')
+            .next()
+            .unwrap_or_default()
+    }
+}
+
+/// 解析 207 Multi-Status, 只认 DAV: 命名空间, 不关心前缀
+fn parse_multistatus(xml: &str) -> Result<Vec<DavEntry>, roxmltree::Error> {
+    let doc = roxmltree::Document::parse(xml)?;
+    let is = |node: &roxmltree::Node, name: &str| {
+        node.is_element()
+            && node.tag_name().name() == name
+            && node.tag_name().namespace() == Some("DAV:")
+    };
+    let entries = doc
+        .descendants()
+        .filter(|node| is(node, "response"))
+        .filter_map(|response| {
+            let find = |name: &str| response.descendants().find(|node| is(node, name));
+            let number = |name: &str| {
+                find(name)
+                    .and_then(|node| node.text())
+                    .and_then(|text| text.trim().parse::<u64>().ok())
+            };
+            let href = find("href")?.text()?.trim();
+            // href 可能是完整的 url, 也可能只有路径
+            let path = match Url::parse(href) {
+                Ok(url) => url.path().to_string(),
+                Err(_) => href.to_string(),
+            };
+            Some(DavEntry {
+                path: percent_decode_str(&path).decode_utf8_lossy().to_string(),
+                collection: find("collection").is_some(),
+                size: number("getcontentlength"),
+                quota_available: number("quota-available-bytes"),
+            })
+        })
+        .collect();
+    Ok(entries)
+}
+
+fn other_error(err: impl std::fmt::Debug) -> io::Error {
+    io::Error::other(format!("{:?}", err))
+}
+
+/// WebDAV, 比如 NAS 或者 alist
+/// 目录布局和本地一样, 下载请求 302 到 public_url
+/// 没有 public_url 时, 只有不需要账号密码或者开启了 redirect_with_credentials 才会 302, 否则由节点转发
+/// ```text
+/// {url}/{base_path}/5d/5d41402abc4b2a76b9719d911017c592
+/// ```
+pub struct WebdavStorage {
+    client: reqClient,
+    /// WebDAV 服务的地址, 以 / 结尾
+    server: Url,
+    /// 存放文件的目录, 以 / 结尾
+    root: Url,
+    /// 从 server 到 root 需要逐级创建的目录
+    base_dirs: Vec<String>,
+    username: Option<String>,
+    password: Option<String>,
+    /// 302 跳转的目录, 以 / 结尾, None 表示由节点转发
+    public: Option<Url>,
+    /// 已经确认存在的目录, 避免每次上传都 MKCOL
+    collections: Mutex<HashSet<String>>,
+}
+
+impl WebdavStorage {
+    pub fn new(config: &WebdavConfig) -> Result<Self, String> {
+        let dir_url = |url: &str| -> Result<Url, String> {
+            let url = format!("{}/", url.trim_end_matches('/'));
+            Url::parse(&url).map_err(|err| format!("invalid url {}: {}", url, err))
+        };
+        let server = dir_url(&config.url)?;
+        let base_dirs: Vec<String> = config
+            .base_path
+            .split('/')
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| format!("{}/", dir))
+            .collect();
+        let root = server
+            .join(&base_dirs.concat())
+            .map_err(|err| format!("invalid base path {}: {}", config.base_path, err))?;
+        let public = match (&config.public_url, &config.username) {
+            (Some(public_url), _) => Some(dir_url(public_url)?),
+            (None, None) => Some(root.clone()),
+            (None, Some(username)) if config.redirect_with_credentials => {
+                let mut public = root.clone();
+                let _ = public.set_username(username);
+                let _ = public.set_password(config.password.as_deref());
+                Some(public)
+            }
+            (None, Some(_)) => None,
+        };
+        let client = reqClient::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .map_err(|err| format!("{:?}", err))?;
+        Ok(Self {
+            client,
+            server,
+            root,
+            base_dirs,
+            username: config.username.clone(),
+            password: config.password.clone(),
+            public,
+            collections: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn url_of(&self, key: &str) -> io::Result<Url> {
+        self.root.join(key).map_err(other_error)
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let req = self.client.request(method, url);
+        match &self.username {
+            Some(username) => req.basic_auth(username, self.password.as_ref()),
+            None => req,
+        }
+    }
+
+    /// 不存在时返回 None
+    async fn propfind(&self, url: Url, depth: &str) -> io::Result<Option<Vec<DavEntry>>> {
+        let res = self
+            .request(Method::from_bytes(b"PROPFIND").unwrap(), url.clone())
+            .header("Depth", depth)
+            .header(header::CONTENT_TYPE, "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await
+            .map_err(other_error)?;
+        match res.status() {
+            StatusCode::NOT_FOUND => return Ok(None),
+            StatusCode::MULTI_STATUS => (),
+            status => return Err(other_error(format!("PROPFIND {} got {}", url, status))),
+        }
+        let body = res.text().await.map_err(other_error)?;
+        parse_multistatus(&body).map(Some).map_err(other_error)
+    }
+
+    /// 逐级 MKCOL 到 root 下的 dir, 已经存在的目录会返回 405
+    async fn ensure_collection(&self, dir: &str) -> io::Result<()> {
+        let parts = dir
+            .split('/')
+            .filter(|part| !part.is_empty())
+            .map(|part| format!("{}/", part));
+        let mut dir = String::new();
+        for part in self.base_dirs.iter().cloned().chain(parts) {
+            dir.push_str(&part);
+            if self.collections.lock().unwrap().contains(&dir) {
+                continue;
+            }
+            let url = self.server.join(&dir).map_err(other_error)?;
+            let res = self
+                .request(Method::from_bytes(b"MKCOL").unwrap(), url.clone())
+                .send()
+                .await
+                .map_err(other_error)?;
+            match res.status() {
+                status if status.is_success() => (),
+                StatusCode::METHOD_NOT_ALLOWED => (),
+                status => return Err(other_error(format!("MKCOL {} got {}", url, status))),
+            }
+            self.collections.lock().unwrap().insert(dir.clone());
+        }
+        Ok(())
+    }
+
+    /// url 下所有分片目录中文件的大小之和
+    async fn shard_sizes(&self, url: Url) -> io::Result<u64> {
+        let mut size = 0;
+        for shard in self.propfind(url.clone(), "1").await?.unwrap_or_default() {
+            if !shard.collection || !is_shard_dir(shard.name()) {
+                continue;
+            }
+            let shard_url = url
+                .join(&format!("{}/", shard.name()))
+                .map_err(other_error)?;
+            size += self
+                .propfind(shard_url, "1")
+                .await?
+                .unwrap_or_default()
+                .iter()
+                .filter(|entry| !entry.collection)
+                .filter_map(|entry| entry.size)
+                .sum::<u64>();
+        }
+        Ok(size)
+    }
+}
+
+#[async_trait]
+impl Storage for WebdavStorage {
+    async fn size(&self, hash: &FileHash) -> io::Result<Option<u64>> {
+        let entries = self.propfind(self.url_of(&hash_key(hash))?, "0").await?;
+        Ok(entries
+            .unwrap_or_default()
+            .into_iter()
+            .find(|entry| !entry.collection)
+            .map(|entry| entry.size.unwrap_or(0)))
+    }
+
+    async fn open(&self, hash: &FileHash, start: u64, len: u64) -> io::Result<FileReader> {
+        let url = self.url_of(&hash_key(hash))?;
+        let mut req = self.request(Method::GET, url.clone());
+        if start > 0 && len > 0 {
+            req = req.header(
+                header::RANGE,
+                format!("bytes={}-{}", start, start + len - 1),
+            );
+        }
+        let res = req.send().await.map_err(other_error)?;
+        match res.status() {
+            StatusCode::NOT_FOUND => return Err(io::ErrorKind::NotFound.into()),
+            StatusCode::PARTIAL_CONTENT => (),
+            // 不支持 Range 的服务会返回整个文件
+            StatusCode::OK if start == 0 => (),
+            status => return Err(other_error(format!("GET {} got {}", url, status))),
+        }
+        let reader = StreamReader::new(res.bytes_stream().map_err(other_error));
+        Ok(Box::pin(reader.take(len)))
+    }
+
+    /// PUT 由服务端保证完整性, 上传失败不会留下半个文件
+    /// reader 出错时请求会被中断, 服务端只收到出错之前的部分, 不会提交
+    async fn write_stream(
+        &self,
+        hash: &FileHash,
+        reader: FileReader,
+        size: Option<u64>,
+    ) -> io::Result<()> {
+        self.ensure_collection(&hash.as_str()[0..2]).await?;
+        let url = self.url_of(&hash_key(hash))?;
+        let mut req = self
+            .request(Method::PUT, url.clone())
+            .body(Body::wrap_stream(ReaderStream::new(reader)));
+        if let Some(size) = size {
+            req = req.header(header::CONTENT_LENGTH, size);
+        }
+        let res = req.send().await.map_err(other_error)?;
+        if !res.status().is_success() {
+            return Err(other_error(format!("PUT {} got {}", url, res.status())));
+        }
+        Ok(())
+    }
+
+    async fn delete_key(&self, key: &str) -> io::Result<()> {
+        check_key(key)?;
+        let url = self.url_of(key)?;
+        let res = self
+            .request(Method::DELETE, url.clone())
+            .send()
+            .await
+            .map_err(other_error)?;
+        match res.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Ok(()),
+            status => Err(other_error(format!("DELETE {} got {}", url, status))),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<StoredFile>> {
+        let mut files = Vec::new();
+        let Some(shards) = self.propfind(self.root.clone(), "1").await? else {
+            return Ok(files);
+        };
+        let root_path = percent_decode_str(self.root.path()).decode_utf8_lossy();
+        for shard in shards {
+            let shard_name = shard.name().to_string();
+            // 第一项是 root 自己
+            if !shard.collection || shard.path == root_path || !is_shard_dir(&shard_name) {
+                continue;
+            }
+            let url = self.url_of(&format!("{}/", shard_name))?;
+            for entry in self.propfind(url, "1").await?.unwrap_or_default() {
+                if entry.collection {
+                    continue;
+                }
+                let name = entry.name();
+                let hash = FileHash::parse(name)
+                    .ok()
+                    .filter(|hash| hash.as_str().starts_with(&shard_name));
+                files.push(StoredFile {
+                    key: format!("{}/{}", shard_name, name),
+                    hash,
+                    size: entry.size.unwrap_or(0),
+                    modified: None,
+                });
+            }
+        }
+        Ok(files)
+    }
+
+    /// MOVE 到回收站, 目录布局和本地一样
+    async fn trash_key(&self, key: &str) -> io::Result<()> {
+        check_key(key)?;
+        let target = trash_key_of(key, SystemTime::now());
+        if let Some((dir, _)) = target.rsplit_once('/') {
+            self.ensure_collection(dir).await?;
+        }
+        let url = self.url_of(key)?;
+        let res = self
+            .request(Method::from_bytes(b"MOVE").unwrap(), url.clone())
+            .header("Destination", self.url_of(&target)?.as_str())
+            .header("Overwrite", "F")
+            .send()
+            .await
+            .map_err(other_error)?;
+        match res.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::NOT_FOUND => Ok(()),
+            status => Err(other_error(format!("MOVE {} got {}", url, status))),
+        }
+    }
+
+    /// 先 PROPFIND 算出批次的大小, 再整个目录 DELETE
+    async fn purge_trash(&self, before: SystemTime) -> io::Result<u64> {
+        let trash = self.url_of(&format!("{}/", TRASH_DIR))?;
+        let Some(batches) = self.propfind(trash, "1").await? else {
+            return Ok(0);
+        };
+        let mut purged = 0;
+        for batch in batches.iter().filter(|entry| entry.collection) {
+            if trash_batch_time(batch.name()).is_none_or(|time| time >= before) {
+                continue;
+            }
+            let dir = format!("{}/{}/", TRASH_DIR, batch.name());
+            let url = self.url_of(&dir)?;
+            let size = self.shard_sizes(url.clone()).await?;
+            let res = self
+                .request(Method::DELETE, url.clone())
+                .send()
+                .await
+                .map_err(other_error)?;
+            match res.status() {
+                status if status.is_success() => (),
+                StatusCode::NOT_FOUND => (),
+                status => return Err(other_error(format!("DELETE {} got {}", url, status))),
+            }
+            // 之后同一个批次的目录要重新 MKCOL
+            let dir = format!("{}{}", self.base_dirs.concat(), dir);
+            self.collections
+                .lock()
+                .unwrap()
+                .retain(|known| !known.starts_with(&dir));
+            purged += size;
+        }
+        Ok(purged)
+    }
+
+    async fn free_space(&self) -> io::Result<Option<u64>> {
+        let entries = self.propfind(self.root.clone(), "0").await?;
+        Ok(entries
+            .unwrap_or_default()
+            .first()
+            .and_then(|entry| entry.quota_available))
+    }
+
+    fn redirect_url(&self, hash: &FileHash) -> Option<String> {
+        let public = self.public.as_ref()?;
+        public.join(&hash_key(hash)).ok().map(String::from)
+    }
+}
+
+#[test]
+fn test_parse_multistatus() {
+    let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+  <D:response>
+    <D:href>/dav/bmclapi/</D:href>
+    <D:propstat>
+      <D:prop>
+        <D:resourcetype><D:collection/></D:resourcetype>
+        <D:quota-available-bytes>1024</D:quota-available-bytes>
+      </D:prop>
+      <D:status>HTTP/1.1 200 OK</D:status>
+    </D:propstat>
+  </D:response>
+  <D:response>
+    <D:href>http://127.0.0.1/dav/bmclapi/5d/a%20b.tmp</D:href>
+    <D:propstat>
+      <D:prop>
+        <D:resourcetype/>
+        <D:getcontentlength>5</D:getcontentlength>
+      </D:prop>
+    </D:propstat>
+  </D:response>
+</D:multistatus>"#;
+    let entries = parse_multistatus(xml).unwrap();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].collection);
+    assert_eq!(entries[0].quota_available, Some(1024));
+    assert_eq!(entries[1].path, "/dav/bmclapi/5d/a b.tmp");
+    assert_eq!(entries[1].name(), "a b.tmp");
+    assert_eq!(entries[1].size, Some(5));
+    assert!(!entries[1].collection);
+}
+
+/// 内存里的 WebDAV 的文件和目录
+#[cfg(test)]
+pub(crate) type FakeDav =
+    std::sync::Arc<Mutex<(std::collections::HashMap<String, Vec<u8>>, HashSet<String>)>>;
+
+/// 内存里的 WebDAV, 只实现了测试用到的部分
+#[cfg(test)]
+async fn fake_webdav(state: FakeDav, req: axum::extract::Request) -> axum::response::Response {
+    use axum::http::{header, StatusCode};
+    use axum::response::IntoResponse;
+    use base64::Engine;
+
+    let auth = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode("admin:secret")
+    );
+    if req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map(|v| v.as_bytes())
+        != Some(auth.as_bytes())
+    {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let path = req.uri().path().trim_end_matches('/').to_string();
+    let parent = path
+        .rsplit_once('/')
+        .map(|(parent, _)| parent)
+        .unwrap_or("");
+    let method = req.method().clone();
+    let range = req
+        .headers()
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes="))
+        .and_then(|v| v.split_once('-'))
+        .map(|(s, e)| (s.parse::<usize>().unwrap(), e.parse::<usize>().unwrap()));
+    let depth = req
+        .headers()
+        .get("Depth")
+        .map(|v| v.to_str().unwrap().to_string());
+    let destination = req
+        .headers()
+        .get("Destination")
+        .and_then(|v| Url::parse(v.to_str().unwrap()).ok())
+        .map(|url| url.path().to_string());
+    // 和真的服务一样, body 没有完整收到的请求不会生效
+    let Ok(body) = axum::body::to_bytes(req.into_body(), usize::MAX).await else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    let mut state = state.lock().unwrap();
+    let (files, dirs) = &mut *state;
+    match method.as_str() {
+        "MKCOL" if dirs.contains(&path) => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+        "MKCOL" if !dirs.contains(parent) => StatusCode::CONFLICT.into_response(),
+        "MKCOL" => {
+            dirs.insert(path);
+            StatusCode::CREATED.into_response()
+        }
+        "PUT" if !dirs.contains(parent) => StatusCode::CONFLICT.into_response(),
+        "PUT" => {
+            files.insert(path, body.to_vec());
+            StatusCode::CREATED.into_response()
+        }
+        "GET" => match (files.get(&path), range) {
+            (Some(data), Some((s, e))) => {
+                (StatusCode::PARTIAL_CONTENT, data[s..=e].to_vec()).into_response()
+            }
+            (Some(data), None) => data.clone().into_response(),
+            (None, _) => StatusCode::NOT_FOUND.into_response(),
+        },
+        "DELETE" if dirs.remove(&path) => {
+            let prefix = format!("{}/", path);
+            files.retain(|p, _| !p.starts_with(&prefix));
+            dirs.retain(|p| !p.starts_with(&prefix));
+            StatusCode::NO_CONTENT.into_response()
+        }
+        "DELETE" => match files.remove(&path) {
+            Some(_) => StatusCode::NO_CONTENT.into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        "MOVE" => {
+            let Some(destination) = destination else {
+                return StatusCode::BAD_REQUEST.into_response();
+            };
+            let dest_parent = destination.rsplit_once('/').map(|(parent, _)| parent);
+            if !dest_parent.is_some_and(|parent| dirs.contains(parent)) {
+                return StatusCode::CONFLICT.into_response();
+            }
+            match files.remove(&path) {
+                Some(data) => {
+                    files.insert(destination, data);
+                    StatusCode::CREATED.into_response()
+                }
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        "PROPFIND" => {
+            let file = |path: &str, size: usize| {
+                format!(
+                    "<D:response><D:href>{}</D:href><D:propstat><D:prop><D:resourcetype/>\
+                     <D:getcontentlength>{}</D:getcontentlength></D:prop></D:propstat></D:response>",
+                    path, size
+                )
+            };
+            let dir = |path: &str| {
+                format!(
+                    "<D:response><D:href>{}/</D:href><D:propstat><D:prop><D:resourcetype>\
+                     <D:collection/></D:resourcetype></D:prop></D:propstat></D:response>",
+                    path
+                )
+            };
+            let mut responses = Vec::new();
+            if let Some(data) = files.get(&path) {
+                responses.push(file(&path, data.len()));
+            } else if dirs.contains(&path) {
+                responses.push(dir(&path));
+                if depth.as_deref() == Some("1") {
+                    let prefix = format!("{}/", path);
+                    let is_child = |p: &String| {
+                        p.strip_prefix(&prefix)
+                            .is_some_and(|rest| !rest.contains('/'))
+                    };
+                    responses.extend(dirs.iter().filter(|p| is_child(p)).map(|p| dir(p)));
+                    responses.extend(
+                        files
+                            .iter()
+                            .filter(|(p, _)| is_child(p))
+                            .map(|(p, data)| file(p, data.len())),
+                    );
+                }
+            } else {
+                return StatusCode::NOT_FOUND.into_response();
+            }
+            let xml = format!(
+                r#"<?xml version="1.0"?><D:multistatus xmlns:D="DAV:">{}</D:multistatus>"#,
+                responses.concat()
+            );
+            (StatusCode::MULTI_STATUS, xml).into_response()
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    }
+}
+
+/// 在随机端口上启动 [`fake_webdav`], 返回它的状态和连接它用的配置
+#[cfg(test)]
+pub(crate) async fn spawn_fake_webdav() -> (FakeDav, WebdavConfig) {
+    let state = FakeDav::default();
+    // 服务根目录 /dav 已经存在
+    state.lock().unwrap().1.insert("/dav".to_string());
+    let app = axum::Router::new().fallback({
+        let state = state.clone();
+        move |req| fake_webdav(state.clone(), req)
+    });
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let config = WebdavConfig {
+        url: format!("http://{}/dav", addr),
+        username: Some("admin".to_string()),
+        password: Some("secret".to_string()),
+        base_path: "/openbmclapi/files".to_string(),
+        public_url: None,
+        redirect_with_credentials: false,
+    };
+    (state, config)
+}
+
+#[tokio::test]
+async fn test_webdav_storage() {
+    let (state, mut config) = spawn_fake_webdav().await;
+    let storage = WebdavStorage::new(&config).unwrap();
+    let hash = FileHash::parse("5d41402abc4b2a76b9719d911017c592").unwrap();
+
+    assert_eq!(storage.size(&hash).await.unwrap(), None);
+    assert_eq!(storage.list().await.unwrap(), vec![]);
+    storage.write(&hash, b"hello").await.unwrap();
+    assert_eq!(storage.size(&hash).await.unwrap(), Some(5));
+    assert!(state
+        .lock()
+        .unwrap()
+        .0
+        .contains_key("/dav/openbmclapi/files/5d/5d41402abc4b2a76b9719d911017c592"));
+
+    let mut part = String::new();
+    let mut reader = storage.open(&hash, 1, 3).await.unwrap();
+    reader.read_to_string(&mut part).await.unwrap();
+    assert_eq!(part, "ell");
+
+    let files = storage.list().await.unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].key, "5d/5d41402abc4b2a76b9719d911017c592");
+    assert_eq!(files[0].hash.as_ref(), Some(&hash));
+    assert_eq!(files[0].size, 5);
+
+    // 默认不把账号密码放进 302 的地址里
+    assert_eq!(storage.redirect_url(&hash), None);
+    config.redirect_with_credentials = true;
+    assert_eq!(
+        WebdavStorage::new(&config)
+            .unwrap()
+            .redirect_url(&hash)
+            .unwrap(),
+        format!(
+            "{}/openbmclapi/files/5d/5d41402abc4b2a76b9719d911017c592",
+            config.url.replace("http://", "http://admin:secret@")
+        )
+    );
+
+    storage.delete(&hash).await.unwrap();
+    storage.delete(&hash).await.unwrap();
+    assert_eq!(storage.size(&hash).await.unwrap(), None);
+
+    // 回收站里的文件不会被列出来, 过期之后整批删除
+    storage.write(&hash, b"hello").await.unwrap();
+    storage.trash_key(&hash_key(&hash)).await.unwrap();
+    assert_eq!(storage.list().await.unwrap(), vec![]);
+    assert_eq!(storage.purge_trash(std::time::UNIX_EPOCH).await.unwrap(), 0);
+    let later = SystemTime::now() + std::time::Duration::from_secs(1);
+    assert_eq!(storage.purge_trash(later).await.unwrap(), 5);
+    assert!(state.lock().unwrap().0.is_empty());
+    // 清空之后还能再放进回收站
+    storage.write(&hash, b"hello").await.unwrap();
+    storage.trash_key(&hash_key(&hash)).await.unwrap();
+    assert_eq!(storage.purge_trash(later).await.unwrap(), 5);
+}
@@ -0,0 +1,207 @@
+use crate::cluster::{Cluster, SyncFile};
+use crate::fetch::{verify_stream, FETCH_IDLE_TIMEOUT};
+use crate::inflight::Flight;
+use crate::state::ClusterState;
+use crate::storage::Storage;
+use crate::utils::FileHash;
+
+use std::collections::HashMap;
+
+use futures_util::{stream, StreamExt};
+use reqwest::Client as reqClient;
+use tokio_util::io::StreamReader;
+use tracing::{info, warn};
+
+/// 同时下载的文件数
+pub const SYNC_CONCURRENCY: usize = 10;
+
+/// 一次同步的结果
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncSummary {
+    /// 下载成功的文件数
+    pub downloaded: usize,
+    /// 本地已经存在, 跳过的文件数
+    pub skipped: usize,
+    /// 下载或者校验失败的文件数
+    pub failed: usize,
+    /// 下载的总字节数
+    pub bytes: u64,
+}
+
+/// 找出存储中缺失或者大小不对的文件
+/// 先 list 一次, 远程存储不用每个文件都请求一次
+pub async fn missing_files(storage: &dyn Storage, files: &[SyncFile]) -> Vec<SyncFile> {
+    let stored: HashMap<FileHash, u64> = match storage.list().await {
+        Ok(stored) => stored
+            .into_iter()
+            .filter_map(|file| Some((file.hash?, file.size)))
+            .collect(),
+        Err(err) => {
+            warn!("list storage failed: {:?}, checking files one by one", err);
+            let mut missing = Vec::new();
+            for file in files {
+                match storage.size(&file.hash).await {
+                    Ok(Some(size)) if size as i64 == file.size => (),
+                    _ => missing.push(file.clone()),
+                }
+            }
+            return missing;
+        }
+    };
+    files
+        .iter()
+        .filter(|file| stored.get(&file.hash).map(|size| *size as i64) != Some(file.size))
+        .cloned()
+        .collect()
+}
+
+impl Cluster {
+    /// ```typescript
+    /// public async syncFiles(fileList: IFileList): Promise<void> {
+    ///   const missingFiles = await this.storage.getMissingFiles(fileList.files)
+    ///   await Bluebird.map(missingFiles, async (file) => {
+    ///     const res = await this.got.get(`openbmclapi/download/${file.hash}`, {
+    ///       searchParams: {noopen: 1},
+    ///     })
+    ///     if (!validateFile(res.body, file.hash)) throw new Error(`文件${file.path}校验失败`)
+    ///     await this.storage.writeFile(hashToFilename(file.hash), res.body, file)
+    ///   }, {concurrency: 10})
+    /// }
+    /// ```
+    pub async fn sync_files(&self, files: &[SyncFile]) -> SyncSummary {
+        // 还没 enable 的时候才切换到 Syncing, enable 之后的定时同步不影响对外服务
+        let syncing = self.state.get() == ClusterState::Connected
+            && self.state.transition(ClusterState::Syncing).is_ok();
+        self.files.update(files);
+
+        let missing = missing_files(self.storage.as_ref(), files).await;
+        let mut summary = SyncSummary {
+            skipped: files.len() - missing.len(),
+            ..Default::default()
+        };
+        info!(
+            "syncing files, total: {}, missing: {}",
+            files.len(),
+            missing.len()
+        );
+        let missing_bytes: u64 = missing.iter().map(|file| file.size.max(0) as u64).sum();
+        match self.storage.free_space().await {
+            Ok(Some(free)) if free < missing_bytes => warn!(
+                "not enough free space, missing: {} bytes, free: {} bytes",
+                missing_bytes, free
+            ),
+            Ok(_) => (),
+            Err(err) => warn!("get free space failed: {:?}", err),
+        }
+
+        let client = reqClient::builder()
+            .user_agent(self.ua.clone())
+            .build()
+            .unwrap();
+        let mut results = stream::iter(missing)
+            .map(|file| {
+                let client = client.clone();
+                async move {
+                    let res = self.download_file(&client, &file).await;
+                    (file, res)
+                }
+            })
+            .buffer_unordered(SYNC_CONCURRENCY);
+        while let Some((file, res)) = results.next().await {
+            match res {
+                Ok(size) => {
+                    summary.downloaded += 1;
+                    summary.bytes += size;
+                }
+                Err(err) => {
+                    warn!("sync file {} ({}) failed: {}", file.path, file.hash, err);
+                    summary.failed += 1;
+                }
+            }
+        }
+
+        info!(
+            "sync finished, downloaded: {}, skipped: {}, failed: {}, bytes: {}",
+            summary.downloaded, summary.skipped, summary.failed, summary.bytes
+        );
+        if syncing && self.state.get() == ClusterState::Syncing {
+            let _ = self.state.transition(ClusterState::Connected);
+        }
+        summary
+    }
+
+    /// 从 center 下载一个文件, 校验后写入存储
+    /// 返回写入的字节数, 如果已经有人在下载这个文件, 等待它完成并返回 0
+    async fn download_file(&self, client: &reqClient, file: &SyncFile) -> Result<u64, String> {
+        let guard = match self.inflight.join(file.hash.as_str()) {
+            Flight::Leader(guard) => guard,
+            Flight::Follower(follower) => {
+                if follower.wait().await {
+                    return Ok(0);
+                }
+                return Err("concurrent fetch failed".to_string());
+            }
+        };
+        let res = self.fetch_file(client, file).await;
+        guard.finish(res.is_ok());
+        res
+    }
+
+    /// 边下载边校验边写入存储, 不在内存里攒整个文件
+    /// 校验失败时写入以错误结束, 不会提交
+    async fn fetch_file(&self, client: &reqClient, file: &SyncFile) -> Result<u64, String> {
+        let req = client
+            .get(self.config.download_url(file.hash.as_str()))
+            .basic_auth(
+                self.config.cluster_id.clone(),
+                Some(self.config.cluster_secret.clone()),
+            )
+            .send();
+        let res = tokio::time::timeout(FETCH_IDLE_TIMEOUT, req)
+            .await
+            .map_err(|_| "request timed out".to_string())?
+            .map_err(|err| format!("request error: {:?}", err))?;
+        if !res.status().is_success() {
+            return Err(format!("net status: {:?}", res.status()));
+        }
+        // 存储会检查写入的大小, 成功时就是这么多字节
+        let len = res.content_length().unwrap_or(file.size.max(0) as u64);
+        let body = verify_stream(res.bytes_stream(), file.hash.clone());
+        self.storage
+            .write_stream(&file.hash, Box::pin(StreamReader::new(body)), Some(len))
+            .await
+            .map_err(|err| format!("write file error: {:?}", err))?;
+        Ok(len)
+    }
+}
+
+#[tokio::test]
+async fn test_missing_files() {
+    let cache_dir = std::env::temp_dir().join("openbmclapi_rs_test_missing_files");
+    let _ = tokio::fs::remove_dir_all(&cache_dir).await;
+    let ok = SyncFile {
+        path: "/a".to_string(),
+        hash: FileHash::parse("5d41402abc4b2a76b9719d911017c592").unwrap(),
+        size: 5,
+        mtime: 0,
+    };
+    let wrong_size = SyncFile {
+        path: "/b".to_string(),
+        hash: FileHash::parse("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d").unwrap(),
+        size: 10,
+        mtime: 0,
+    };
+    let absent = SyncFile {
+        path: "/c".to_string(),
+        hash: FileHash::parse("0123456789abcdef0123456789abcdef").unwrap(),
+        size: 1,
+        mtime: 0,
+    };
+    let storage = crate::storage::LocalStorage::new(cache_dir.clone());
+    storage.write(&ok.hash, b"hello").await.unwrap();
+    storage.write(&wrong_size.hash, b"hello").await.unwrap();
+    let missing = missing_files(&storage, &[ok, wrong_size, absent]).await;
+    let missing: Vec<&str> = missing.iter().map(|f| f.path.as_str()).collect();
+    assert_eq!(missing, vec!["/b", "/c"]);
+    tokio::fs::remove_dir_all(&cache_dir).await.unwrap();
+}
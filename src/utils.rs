@@ -1,23 +1,80 @@
 use crate::cluster::SyncFile;
 
 use std::collections::HashMap;
+use std::fmt;
 use std::io::Cursor;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use apache_avro::{from_avro_datum, from_value, types::Value};
 use base64::Engine;
 use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
 use sha1::Sha1;
 use tokio::io::AsyncWriteExt;
 use tracing::{info, warn};
 
+/// 校验过的文件 hash
+/// 只接受 32 位 (md5) 或者 40 位 (sha1) 的小写十六进制,
+/// 所以可以放心地拿来拼接路径
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FileHash(String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidHash(pub String);
+
+impl fmt::Display for InvalidHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid file hash: {:?}", self.0)
+    }
+}
+
+impl FileHash {
+    pub fn parse(hash: &str) -> Result<Self, InvalidHash> {
+        let valid_len = hash.len() == 32 || hash.len() == 40;
+        let valid_char = hash
+            .bytes()
+            .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c));
+        if valid_len && valid_char {
+            Ok(Self(hash.to_string()))
+        } else {
+            Err(InvalidHash(hash.to_string()))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for FileHash {
+    type Error = InvalidHash;
+
+    fn try_from(hash: String) -> Result<Self, Self::Error> {
+        Self::parse(&hash)
+    }
+}
+
+impl From<FileHash> for String {
+    fn from(hash: FileHash) -> Self {
+        hash.0
+    }
+}
+
+impl fmt::Display for FileHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
 /// import {join} from 'path'
 ///
 /// export function hashToFilename(hash: string): string {
 ///     // eslint-disable-next-line @typescript-eslint/no-magic-numbers
 ///     return join(hash.substring(0, 2), hash)
 ///   }
-pub fn hash_to_filename(hash: &str) -> PathBuf {
+pub fn hash_to_filename(hash: &FileHash) -> PathBuf {
+    let hash = hash.as_str();
     let mut path = PathBuf::new();
     path.push(&hash[0..2]);
     path.push(hash);
@@ -37,22 +94,40 @@ pub fn hash_to_filename(hash: &str) -> PathBuf {
 ///     return hash.digest('hex') === checkSum
 ///   }
 pub fn validate_file(buffer: &[u8], check_sum: &str) -> bool {
-    match check_sum.len() {
-        32 => {
-            let mut hasher = Md5::new();
-            hasher.update(buffer);
-            let result = hasher.finalize();
-            let result_str = format!("{:x}", result);
-            result_str == check_sum
+    let mut hasher = FileHasher::new(check_sum);
+    hasher.update(buffer);
+    hasher.matches(check_sum)
+}
+
+/// 流式版本的 validate_file, 用于边下载边校验
+pub enum FileHasher {
+    Md5(Md5),
+    Sha1(Sha1),
+}
+
+impl FileHasher {
+    /// 和 validate_file 一样, 32 位用 md5, 其他用 sha1
+    pub fn new(check_sum: &str) -> Self {
+        match check_sum.len() {
+            32 => Self::Md5(Md5::new()),
+            _ => Self::Sha1(Sha1::new()),
         }
-        _ => {
-            let mut hasher = Sha1::new();
-            hasher.update(buffer);
-            let result = hasher.finalize();
-            let result_str = format!("{:x}", result);
-            result_str == check_sum
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
         }
     }
+
+    pub fn matches(self, check_sum: &str) -> bool {
+        let result_str = match self {
+            Self::Md5(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Sha1(hasher) => format!("{:x}", hasher.finalize()),
+        };
+        result_str == check_sum
+    }
 }
 
 /// export function checkSign(hash: string, secret: string, query: NodeJS.Dict<string>): boolean {
@@ -80,7 +155,7 @@ pub fn check_sign(hash: &str, secret: &str, query: &HashMap<String, String>) ->
     let result_str = base64::engine::general_purpose::URL_SAFE.encode(&result);
     // 1970 年 1 月 1 日 00:00:00 (UTC) 到当前时间的毫秒数。
     let now = chrono::Utc::now().timestamp_millis();
-    &result_str == s && now < i64::from_str_radix(e, 36).unwrap()
+    &result_str == s && i64::from_str_radix(e, 36).is_ok_and(|e| now < e)
 }
 
 /// BYD avro 格式的文件列表
@@ -93,7 +168,8 @@ pub const SYNC_FILE_LIST_SCHEMA: &str = r#"
         "fields": [
             {"name": "path", "type": "string"},
             {"name": "hash", "type": "string"},
-            {"name": "size", "type": "long"}
+            {"name": "size", "type": "long"},
+            {"name": "mtime", "type": "long"}
         ]
     }
 }
@@ -133,17 +209,52 @@ pub fn avro_data_to_file_list(data: Vec<u8>) -> Option<Vec<SyncFile>> {
     }
 }
 
+/// 先写到同目录下的临时文件, 再 rename 过去
+/// 这样其他人永远不会读到写了一半的文件
 pub async fn safe_write_file(path: &PathBuf, data: &[u8]) -> Result<(), std::io::Error> {
-    let mut file = tokio::fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .open(path)
-        .await?;
+    write_then_rename(path, data, false).await
+}
+
+/// 和 [`safe_write_file`] 一样, 但是在 unix 上只有自己可以读写 (0600), 用来保存私钥
+pub async fn safe_write_private_file(path: &PathBuf, data: &[u8]) -> Result<(), std::io::Error> {
+    write_then_rename(path, data, true).await
+}
+
+async fn write_then_rename(
+    path: &PathBuf,
+    data: &[u8],
+    private: bool,
+) -> Result<(), std::io::Error> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = tmp_file_path(path);
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        options.mode(0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+    let mut file = options.open(&tmp_path).await?;
     file.write_all(data).await?;
     file.sync_all().await?;
-    Ok(())
+    drop(file);
+    tokio::fs::rename(&tmp_path, path).await
+}
+
+/// 写文件时使用的临时文件名
+/// 带一个随机数, 同时写同一个文件时不会互相覆盖
+pub fn tmp_file_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{:08x}{}", rand::random::<u32>(), TMP_FILE_SUFFIX));
+    path.with_file_name(name)
 }
 
+/// 临时文件的后缀
+pub const TMP_FILE_SUFFIX: &str = ".tmp";
+
 /// FATAL 级 Log
 /// 这个宏会输出一条 error 级的日志, 并且 panic!
 /// 这个宏应当接收两个参数, 分别定义为 arg1 和 arg2, 其应当均为 String 类型
@@ -179,12 +290,25 @@ macro_rules! fatal {
 
 #[test]
 fn test_hash_to_filename() {
+    let hash = FileHash::parse("1234567890abcdef1234567890abcdef").unwrap();
     assert_eq!(
-        hash_to_filename("1234567890abcdef"),
-        PathBuf::from("12/1234567890abcdef")
+        hash_to_filename(&hash),
+        PathBuf::from("12/1234567890abcdef1234567890abcdef")
     );
 }
 
+#[test]
+fn test_file_hash() {
+    assert!(FileHash::parse("5d41402abc4b2a76b9719d911017c592").is_ok());
+    assert!(FileHash::parse("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d").is_ok());
+    assert!(FileHash::parse("").is_err());
+    assert!(FileHash::parse("a").is_err());
+    assert!(FileHash::parse("5D41402ABC4B2A76B9719D911017C592").is_err());
+    assert!(FileHash::parse("../../../../../../../etc/passwd.").is_err());
+    assert!(FileHash::parse("我41402abc4b2a76b9719d911017c5").is_err());
+    assert!(serde_json::from_str::<FileHash>("\"zz\"").is_err());
+}
+
 #[test]
 fn test_validate_file() {
     assert_eq!(
@@ -197,6 +321,14 @@ fn test_validate_file() {
     );
 }
 
+#[test]
+fn test_file_hasher() {
+    let mut hasher = FileHasher::new("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
+    hasher.update(b"hel");
+    hasher.update(b"lo");
+    assert!(hasher.matches("aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d"));
+}
+
 #[test]
 fn test_check_sign() {
     let mut query = HashMap::new();